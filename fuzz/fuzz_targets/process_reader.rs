@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use payments_engine::PaymentsEngine;
+
+fuzz_target!(|data: &[u8]| {
+    let mut engine = PaymentsEngine::new(String::new());
+    engine.process_reader(data);
+    engine.check_invariants().expect("Invariants hold");
+
+    let mut buf = Vec::new();
+    engine.print_accounts(&mut buf);
+});
@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use crate::clock::Clock;
+use crate::config::{EngineConfig, LockedTxBehavior};
+use crate::engine::PaymentsEngine;
+
+/// Fluent configuration of a [`PaymentsEngine`]
+///
+/// Options that are not set keep the defaults of [`EngineConfig`].
+#[derive(Default)]
+pub struct PaymentsEngineBuilder {
+    input_file: String,
+    config: EngineConfig,
+    clock: Option<Box<dyn Clock>>,
+    explain: Option<Box<dyn Write>>,
+}
+
+impl PaymentsEngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Path of the file or directory processed by [`PaymentsEngine::run`]
+    pub fn input_file<S: Into<String>>(mut self, input_file: S) -> Self {
+        self.input_file = input_file.into();
+        self
+    }
+
+    /// Replace all the options with the given config
+    pub fn config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Reject dispute rows carrying an amount, see [`EngineConfig::strict_dispute_rows`]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict_dispute_rows = strict;
+        self
+    }
+
+    /// Round the printed totals, see [`EngineConfig::total_scale`]
+    pub fn total_scale(mut self, scale: u32) -> Self {
+        self.config.total_scale = Some(scale);
+        self
+    }
+
+    /// Round the amounts when parsed, see [`EngineConfig::amount_scale`]
+    pub fn amount_scale(mut self, scale: u32) -> Self {
+        self.config.amount_scale = Some(scale);
+        self
+    }
+
+    /// See [`EngineConfig::withdrawal_disputes`]
+    pub fn withdrawal_disputes(mut self, enabled: bool) -> Self {
+        self.config.withdrawal_disputes = enabled;
+        self
+    }
+
+    /// See [`EngineConfig::max_accounts`]
+    pub fn max_accounts(mut self, max: usize) -> Self {
+        self.config.max_accounts = Some(max);
+        self
+    }
+
+    /// See [`EngineConfig::max_open_disputes_per_client`]
+    pub fn max_open_disputes_per_client(mut self, max: usize) -> Self {
+        self.config.max_open_disputes_per_client = Some(max);
+        self
+    }
+
+    /// See [`EngineConfig::locked_tx_behavior`]
+    pub fn locked_tx_behavior(mut self, behavior: LockedTxBehavior) -> Self {
+        self.config.locked_tx_behavior = behavior;
+        self
+    }
+
+    /// See [`EngineConfig::track_arrival`]
+    pub fn track_arrival(mut self, enabled: bool) -> Self {
+        self.config.track_arrival = enabled;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_clock`]
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// See [`PaymentsEngine::with_explain_writer`]
+    pub fn explain_writer<W: Write + 'static>(mut self, writer: W) -> Self {
+        self.explain = Some(Box::new(writer));
+        self
+    }
+
+    pub fn build(self) -> PaymentsEngine {
+        let mut engine = PaymentsEngine::with_config(self.input_file, self.config);
+        if let Some(clock) = self.clock {
+            engine = engine.with_clock(clock);
+        }
+        if let Some(explain) = self.explain {
+            engine = engine.with_explain_writer(explain);
+        }
+        engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::engine::{Error, Tx, TxType};
+
+    #[test]
+    fn test_builder() {
+        let mut engine = PaymentsEngineBuilder::new()
+            .input_file("examples/disputes.csv")
+            .withdrawal_disputes(true)
+            .total_scale(2)
+            .build();
+        assert!(engine.config().withdrawal_disputes);
+        assert_eq!(engine.config().total_scale, Some(2));
+        assert!(!engine.config().strict_dispute_rows);
+        engine.run().expect("Input is processed");
+
+        // Withdrawals can be disputed
+        engine
+            .process_tx(Tx::new(
+                TxType::Withdrawal,
+                1,
+                10,
+                Some(Decimal::new(40, 0)),
+            ))
+            .expect("Withdrawal is valid");
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 10, None))
+            .expect("Withdrawal can be disputed");
+        assert_eq!(engine.held(1), Some(Decimal::new(40, 0)));
+
+        let mut engine = PaymentsEngine::builder().strict(true).build();
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 1, Some(Decimal::ONE)))
+            .expect("Deposit is valid");
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, Some(Decimal::ONE))),
+            Err(Error::UnexpectedAmount(1))
+        ));
+    }
+}
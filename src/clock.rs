@@ -0,0 +1,51 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the sequence numbers the engine uses to order account activity
+pub trait Clock {
+    /// Returns the current sequence number, which must never decrease between calls
+    fn now(&mut self) -> u64;
+}
+
+/// Wall clock returning the number of nanoseconds since the Unix epoch
+///
+/// The returned value is bumped if the system time goes backwards, so it stays monotonic.
+#[derive(Debug, Default)]
+pub struct SystemClock {
+    last: u64,
+}
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        self.last = now.max(self.last + 1);
+        self.last
+    }
+}
+
+/// Deterministic clock returning consecutive numbers, starting from a given value
+#[derive(Debug, Default)]
+pub struct SequenceClock {
+    next: u64,
+}
+
+impl SequenceClock {
+    pub fn starting_at(next: u64) -> Self {
+        Self { next }
+    }
+}
+
+impl Clock for SequenceClock {
+    fn now(&mut self) -> u64 {
+        let now = self.next;
+        self.next += 1;
+        now
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&mut self) -> u64 {
+        (**self).now()
+    }
+}
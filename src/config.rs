@@ -0,0 +1,343 @@
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// What to do with transactions for locked accounts
+///
+/// Resolves and chargebacks are always applied, so that the disputes still open when an account
+/// is locked can be closed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedTxBehavior {
+    /// Reject them with [`Error::AccountLocked`](crate::Error::AccountLocked)
+    #[default]
+    Error,
+    /// Drop them without reporting an error, only counting them in the stats
+    SilentSkip,
+    /// Drop them like [`SilentSkip`](Self::SilentSkip), but still apply disputes, for replays of
+    /// transactions that may predate the lock
+    Replay,
+}
+
+/// What to do with withdrawals that would leave a dust balance, see
+/// [`EngineConfig::dust_threshold`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DustAction {
+    /// Reject them with [`Error::DustBalance`](crate::Error::DustBalance)
+    #[default]
+    Reject,
+    /// Withdraw the dust along with the amount, draining the available funds
+    Sweep,
+}
+
+/// Whitespace trimmed from the CSV input
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrimPolicy {
+    /// Keep all whitespace, so that padded numbers fail to parse
+    None,
+    /// Trim the header row only
+    Headers,
+    /// Trim the fields of the records only
+    Fields,
+    /// Trim the header row and the fields
+    #[default]
+    All,
+}
+
+impl From<TrimPolicy> for csv::Trim {
+    fn from(policy: TrimPolicy) -> Self {
+        match policy {
+            TrimPolicy::None => csv::Trim::None,
+            TrimPolicy::Headers => csv::Trim::Headers,
+            TrimPolicy::Fields => csv::Trim::Fields,
+            TrimPolicy::All => csv::Trim::All,
+        }
+    }
+}
+
+/// Which `client_name` is kept as the label of an account
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LabelPolicy {
+    /// The name from the first transaction of the client
+    #[default]
+    First,
+    /// The name from the latest transaction of the client
+    Latest,
+}
+
+/// Column of the printed accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Label,
+}
+
+impl OutputColumn {
+    /// All the columns, in their default order
+    pub const ALL: [OutputColumn; 6] = [
+        OutputColumn::Client,
+        OutputColumn::Available,
+        OutputColumn::Held,
+        OutputColumn::Total,
+        OutputColumn::Locked,
+        OutputColumn::Label,
+    ];
+
+    /// Name of the column in the header
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputColumn::Client => "client",
+            OutputColumn::Available => "available",
+            OutputColumn::Held => "held",
+            OutputColumn::Total => "total",
+            OutputColumn::Locked => "locked",
+            OutputColumn::Label => "label",
+        }
+    }
+}
+
+/// Options controlling how the engine reads, processes and prints transactions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineConfig {
+    /// Capacity of the buffer wrapping the input file, if not using the reader default
+    pub read_buffer_bytes: Option<usize>,
+    /// Whitespace trimmed from the CSV input
+    pub trim: TrimPolicy,
+    /// Delimiter of the fields of the CSV input
+    pub delimiter: u8,
+    /// Parse the amounts with a comma as the decimal separator (e.g. `50,00`), which requires
+    /// another [`delimiter`](Self::delimiter) for the CSV input, such as `;`
+    pub decimal_comma: bool,
+    /// Trim the whitespace around the client and tx ids whatever the [`trim`](Self::trim)
+    /// policy, and accept the ids of JSON lines given as (possibly padded) strings
+    pub normalize_ids: bool,
+    /// Accept client and tx ids written in hex with a `0x` prefix, or in decimal with
+    /// underscores separating the digits (e.g. `1_000`)
+    pub lenient_ids: bool,
+    /// Skip the records of the input with a timestamp before this one, only counting them in
+    /// the stats
+    ///
+    /// The records without a timestamp are processed whatever the window, so that they can
+    /// still dispute the transactions within it.
+    pub from_timestamp: Option<u64>,
+    /// Skip the records of the input with a timestamp at or after this one, like
+    /// [`from_timestamp`](Self::from_timestamp)
+    pub to_timestamp: Option<u64>,
+    /// Only process the records from these channels (the `channel` column of the input),
+    /// only counting the others in the stats
+    ///
+    /// Like the records without a timestamp, the records without a channel are always
+    /// processed, so that they can still dispute the transactions of the selected channels.
+    pub channels: Option<Vec<String>>,
+    /// Number of threads parsing the CSV input files in parallel, each parsing blocks of rows
+    /// in turn, while the records are applied in their original order
+    ///
+    /// The input is streamed, a few blocks per thread being in memory at a time, and the
+    /// blocks are split outside of the quoted fields, which may contain line breaks.
+    pub parse_threads: NonZeroUsize,
+    /// Validate the whole input file before processing it, and reject it without processing
+    /// any record if one of them can't be parsed
+    pub all_or_nothing: bool,
+    /// Abort the processing with [`Error::TooManyErrors`](crate::Error::TooManyErrors) once
+    /// more than this many records of the input were rejected or malformed
+    pub max_errors: Option<u64>,
+    /// Check the balances of the accounts touched by every transaction right after it is
+    /// applied, and abort the processing with
+    /// [`Error::StepInvariantViolation`](crate::Error::StepInvariantViolation) if one of them
+    /// is inconsistent
+    ///
+    /// This catches arithmetic bugs at the transaction causing them, at the cost of a check
+    /// per transaction.
+    pub check_invariants_each_step: bool,
+    /// Only every n-th record of the input is processed
+    ///
+    /// This is meant for smoke tests and profiling of large inputs. The resulting balances are
+    /// approximate since the skipped records may be referenced by later disputes.
+    pub sample_rate: NonZeroUsize,
+    /// Round the amounts with more than 4 decimal places to 4 decimal places when parsed, if
+    /// they are at most this far from the rounded value (e.g. `50.0000000000001` from float
+    /// serialization upstream), and reject them with
+    /// [`Error::ImpreciseAmount`](crate::Error::ImpreciseAmount) otherwise
+    ///
+    /// The snapping is done before the rounding of [`amount_scale`](Self::amount_scale).
+    pub snap_epsilon: Option<Decimal>,
+    /// Number of decimal places amounts are rounded to when parsed, using banker's rounding
+    ///
+    /// Rounding events and the precision they discard are counted in the stats.
+    pub amount_scale: Option<u32>,
+    /// Print a warning for every amount that loses precision when rounded
+    pub warn_on_rounding: bool,
+    /// Number of decimal places the amounts of deposits, withdrawals and transfers are rounded
+    /// to before they are applied, rounding midpoints away from zero
+    ///
+    /// If [`amount_scale`](Self::amount_scale) is also set, it is applied first, when the
+    /// amount is parsed. Unlike it, this rounding is not counted in the stats.
+    pub round_input: Option<u32>,
+    /// Strip the trailing zeros of the amounts of deposits, withdrawals and transfers when they
+    /// are retained, so that their disputes hold a canonical amount whatever the way it was
+    /// written (`50` or `50.00`)
+    pub normalize_stored_amounts: bool,
+    /// Maximum number of disputes a client may have open at once
+    pub max_open_disputes_per_client: Option<usize>,
+    /// Maximum funds a client may withdraw per UTC day
+    ///
+    /// Only the withdrawals with a timestamp count towards the limit and are checked against it.
+    pub daily_withdrawal_limit: Option<Decimal>,
+    /// Withdrawals leaving available funds below this amount, but above zero, are handled
+    /// according to [`dust_action`](Self::dust_action)
+    pub dust_threshold: Option<Decimal>,
+    /// What to do with withdrawals that would leave a dust balance
+    pub dust_action: DustAction,
+    /// Maximum number of client accounts, transactions that would create more are rejected
+    pub max_accounts: Option<usize>,
+    /// What to do with transactions for locked accounts, see [`LockedTxBehavior`]
+    pub locked_tx_behavior: LockedTxBehavior,
+    /// Ignore resolves and chargebacks of transactions that are not under dispute instead of
+    /// reporting an error, only counting them in the stats
+    pub ignore_stray_resolves: bool,
+    /// Allow withdrawals to be disputed
+    ///
+    /// A disputed withdrawal holds the withdrawn amount pending its return, without touching the
+    /// available funds. A resolve releases the held funds, while a chargeback moves them to the
+    /// available funds and locks the account.
+    pub withdrawal_disputes: bool,
+    /// Reject disputes, resolves and chargebacks that carry an amount with
+    /// [`Error::UnexpectedAmount`](crate::Error::UnexpectedAmount), which disables partial
+    /// disputes
+    pub strict_dispute_rows: bool,
+    /// Hold the disputes of transactions that have not been seen yet, and apply them once the
+    /// transaction they reference is processed, instead of rejecting them
+    ///
+    /// Only the records of the input are buffered, not the transactions passed to
+    /// [`PaymentsEngine::process_tx`](crate::PaymentsEngine::process_tx).
+    pub buffer_orphan_disputes: bool,
+    /// Reject deposits and withdrawals reusing the id of an earlier deposit or withdrawal with
+    /// [`Error::IdReused`](crate::Error::IdReused), even if it was charged back
+    pub forbid_id_reuse: bool,
+    /// Accept deposits and withdrawals of a zero amount as no-ops instead of rejecting them
+    pub allow_zero_amount: bool,
+    /// Treat deposits with a negative amount as withdrawals of the absolute amount instead of
+    /// rejecting them
+    pub negative_is_withdrawal: bool,
+    /// Give every transaction an increasing arrival index, kept on the retained transactions
+    pub track_arrival: bool,
+    /// Also give an arrival index to the rejected transactions, so that the indices count
+    /// every transaction of the input
+    pub count_rejected_arrivals: bool,
+    /// Also reject disputes, resolves and chargebacks while the engine is paused
+    pub pause_disputes: bool,
+    /// Disputes open for this many days or less are not charged penalties
+    pub penalty_threshold_days: u64,
+    /// Only write the CSV header on the first `print_accounts` call, so that repeated
+    /// snapshots can be appended to the same output stream
+    pub omit_repeated_header: bool,
+    /// End the printed lines with `\r\n` instead of `\n`
+    pub crlf: bool,
+    /// Print the funds as integers of minor units with this many decimal places per major unit
+    /// (e.g. cents for 2) instead of decimals
+    pub output_minor_units: Option<u32>,
+    /// Only print the accounts with negative available funds
+    pub negative_only: bool,
+    /// Append a row with the client `*` summing the funds of all accounts to the output
+    pub grand_total: bool,
+    /// Append the label of each account (see [`Account::label`](crate::Account::label)) to the
+    /// output
+    pub with_label: bool,
+    /// Which `client_name` of a client is kept as its label
+    pub label_policy: LabelPolicy,
+    /// Write a snapshot of the engine to [`checkpoint_path`](Self::checkpoint_path) every n
+    /// records of the input
+    pub checkpoint_every: Option<NonZeroUsize>,
+    /// Drop the retained withdrawals that can't be disputed every n records of the input, see
+    /// [`PaymentsEngine::compact`](crate::PaymentsEngine::compact)
+    pub compact_every: Option<NonZeroUsize>,
+    /// File the checkpoints are written to, removed once the whole input is processed by
+    /// [`PaymentsEngine::run`](crate::PaymentsEngine::run)
+    pub checkpoint_path: Option<PathBuf>,
+    /// Number of decimal places the printed totals are rounded to, independently of the
+    /// available and held funds
+    pub total_scale: Option<u32>,
+    /// Columns of the printed accounts, in order, instead of the default ones
+    pub columns: Option<Vec<OutputColumn>>,
+    /// Names given to the columns in the printed CSV header instead of their default
+    /// [`name`](OutputColumn::name), without changing the values or their order
+    pub column_names: Vec<(OutputColumn, String)>,
+}
+
+impl EngineConfig {
+    /// Name of the column in the printed CSV header, after the renames of
+    /// [`column_names`](Self::column_names)
+    pub fn column_name(&self, column: OutputColumn) -> &str {
+        self.column_names
+            .iter()
+            .find(|(renamed, _)| *renamed == column)
+            .map_or(column.name(), |(_, name)| name)
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            read_buffer_bytes: None,
+            trim: TrimPolicy::default(),
+            delimiter: b',',
+            decimal_comma: false,
+            normalize_ids: false,
+            lenient_ids: false,
+            from_timestamp: None,
+            to_timestamp: None,
+            channels: None,
+            parse_threads: NonZeroUsize::MIN,
+            all_or_nothing: false,
+            max_errors: None,
+            check_invariants_each_step: false,
+            sample_rate: NonZeroUsize::MIN,
+            snap_epsilon: None,
+            amount_scale: None,
+            warn_on_rounding: false,
+            round_input: None,
+            normalize_stored_amounts: false,
+            max_open_disputes_per_client: None,
+            max_accounts: None,
+            daily_withdrawal_limit: None,
+            dust_threshold: None,
+            dust_action: DustAction::default(),
+            locked_tx_behavior: LockedTxBehavior::default(),
+            ignore_stray_resolves: false,
+            withdrawal_disputes: false,
+            strict_dispute_rows: false,
+            buffer_orphan_disputes: false,
+            forbid_id_reuse: false,
+            allow_zero_amount: false,
+            negative_is_withdrawal: false,
+            track_arrival: false,
+            count_rejected_arrivals: false,
+            pause_disputes: false,
+            penalty_threshold_days: 0,
+            omit_repeated_header: false,
+            crlf: false,
+            output_minor_units: None,
+            negative_only: false,
+            grand_total: false,
+            with_label: false,
+            label_policy: LabelPolicy::default(),
+            total_scale: None,
+            columns: None,
+            column_names: Vec::new(),
+            checkpoint_every: None,
+            checkpoint_path: None,
+            compact_every: None,
+        }
+    }
+}
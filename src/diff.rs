@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use csv::{ReaderBuilder, Trim};
+
+use crate::config::EngineConfig;
+use crate::engine::{AccountSummary, PaymentsEngine, Result};
+
+/// Difference between the summaries of a client's account in two account outputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDiff {
+    pub client: u16,
+    pub left: Option<AccountSummary>,
+    pub right: Option<AccountSummary>,
+}
+
+impl fmt::Display for AccountDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (left, right) = match (&self.left, &self.right) {
+            (Some(left), Some(right)) => (left, right),
+            (Some(_), None) => return write!(f, "client {}: only in the first file", self.client),
+            (None, _) => return write!(f, "client {}: only in the second file", self.client),
+        };
+        write!(f, "client {}:", self.client)?;
+        let mut sep = " ";
+        if left.available != right.available {
+            write!(
+                f,
+                "{}available {} != {}",
+                sep, left.available, right.available
+            )?;
+            sep = ", ";
+        }
+        if left.held != right.held {
+            write!(f, "{}held {} != {}", sep, left.held, right.held)?;
+            sep = ", ";
+        }
+        if left.locked != right.locked {
+            write!(f, "{}locked {} != {}", sep, left.locked, right.locked)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the account summaries printed by
+/// [`PaymentsEngine::print_accounts`](crate::PaymentsEngine::print_accounts)
+pub fn read_summaries<R: std::io::Read>(reader: R) -> csv::Result<Vec<AccountSummary>> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        .collect()
+}
+
+/// Compare two sets of account summaries, returning the clients whose available funds, held
+/// funds or locked state differ, sorted by client id
+///
+/// Clients present in only one of the sets are reported as well.
+pub fn diff_summaries(left: &[AccountSummary], right: &[AccountSummary]) -> Vec<AccountDiff> {
+    let mut clients: BTreeMap<u16, AccountDiff> = BTreeMap::new();
+    for summary in left {
+        clients
+            .entry(summary.client)
+            .or_insert_with(|| empty_diff(summary.client))
+            .left = Some(summary.clone());
+    }
+    for summary in right {
+        clients
+            .entry(summary.client)
+            .or_insert_with(|| empty_diff(summary.client))
+            .right = Some(summary.clone());
+    }
+    clients
+        .into_values()
+        .filter(|diff| match (&diff.left, &diff.right) {
+            (Some(left), Some(right)) => {
+                left.available != right.available
+                    || left.held != right.held
+                    || left.locked != right.locked
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// Process the same input under two configurations and compare the resulting accounts, to
+/// evaluate a change of policy
+///
+/// The input may be a file or a directory, as for [`PaymentsEngine::run`]. The accounts
+/// processed under `a` are on the left of the differences.
+pub fn compare_policies(
+    input: &Path,
+    a: EngineConfig,
+    b: EngineConfig,
+) -> Result<Vec<AccountDiff>> {
+    let input = input.to_string_lossy();
+    let mut left = PaymentsEngine::with_config(input.to_string(), a);
+    left.run()?;
+    let mut right = PaymentsEngine::with_config(input.to_string(), b);
+    right.run()?;
+    Ok(diff_summaries(&left.summaries(), &right.summaries()))
+}
+
+fn empty_diff(client: u16) -> AccountDiff {
+    AccountDiff {
+        client,
+        left: None,
+        right: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LockedTxBehavior;
+
+    #[test]
+    fn test_diff_summaries() {
+        let a = "client,available,held,total,locked\n\
+            1,100,0,100,false\n\
+            2,50.5,10,60.5,false\n\
+            3,0,0,0,true\n";
+        let b = "client, available, held, total, locked\n\
+            1,100.00,0,100,false\n\
+            2,50.5,0,50.5,true\n\
+            3,0,0,0,true\n";
+        let a = read_summaries(a.as_bytes()).expect("Summaries are valid");
+        let b = read_summaries(b.as_bytes()).expect("Summaries are valid");
+
+        let diffs = diff_summaries(&a, &b);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client, 2);
+        assert_eq!(
+            diffs[0].to_string(),
+            "client 2: held 10 != 0, locked false != true"
+        );
+
+        let diffs = diff_summaries(&a, &b[..2]);
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[1].to_string(), "client 3: only in the first file");
+    }
+
+    #[test]
+    fn test_compare_policies() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-policies-{}.csv",
+            std::process::id()
+        ));
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,2,20\n\
+            deposit,2,3,30\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            dispute,1,2,\n";
+        std::fs::write(&path, input).expect("Input is written");
+        let replay = EngineConfig {
+            locked_tx_behavior: LockedTxBehavior::Replay,
+            ..Default::default()
+        };
+        let diffs = compare_policies(&path, EngineConfig::default(), replay);
+        std::fs::remove_file(&path).expect("Input is removed");
+
+        // Only the replayed dispute on the locked account makes a difference
+        let diffs = diffs.expect("Input is processed");
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            diffs[0].to_string(),
+            "client 1: available 20 != 0, held 0 != 20"
+        );
+    }
+}
@@ -1,14 +1,38 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use csv::{ReaderBuilder, Trim};
-use rust_decimal::Decimal;
+use csv::{ByteRecord, ReaderBuilder, Terminator, WriterBuilder};
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-type Result<T> = std::result::Result<T, Error>;
+use crate::builder::PaymentsEngineBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{DustAction, EngineConfig, LabelPolicy, LockedTxBehavior, OutputColumn};
+#[cfg(feature = "msgpack")]
+use crate::msgpack;
+use crate::progress::{Progress, ProgressReader};
+use crate::report::ErrorReport;
+use crate::store::Store;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// Decimal places of the amounts of the input, which snapped amounts are rounded to
+const SNAP_SCALE: u32 = 4;
+// Size of the blocks of rows parsed by the threads of `EngineConfig::parse_threads`
+const PARSE_BLOCK_SIZE: usize = 1 << 20;
+
+pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
-enum Error {
+pub enum Error {
     #[error("Account (id: {0}) is locked")]
     AccountLocked(u16),
     #[error("Transaction (id: {0}) does not have an amount")]
@@ -31,58 +55,826 @@ enum Error {
     ClientIdMismatch(TxType, u32),
     #[error("Invalid {0:?} as it specifies an amount")]
     TxSpecifiesAmount(TxType),
+    #[error("Dispute of transaction (id: {0}) exceeds the amount of the original transaction")]
+    OverDispute(u32),
+    #[error("Transfer (id: {0}) does not specify a valid destination client")]
+    InvalidTransferDest(u32),
+    #[error("Transaction (id: {0}) has already been charged back")]
+    AlreadyChargedBack(u32),
+    #[error("Amount of transaction (id: {0}) is not a valid decimal")]
+    AmountParse(u32),
+    #[error("Client (id: {0}) has too many open disputes")]
+    TooManyOpenDisputes(u16),
+    #[error(
+        "Account for client (id: {0}) can't be created, the maximum number of accounts is reached"
+    )]
+    TooManyAccounts(u16),
+    #[error("Transaction (id: {0}) would overflow the account balance")]
+    Overflow(u32),
+    #[error("Invariant violated for account (id: {0}): {1}")]
+    InvariantViolation(u16, &'static str),
+    #[error("Invariant violated for account (id: {1}) after transaction (id: {0}): {2}")]
+    StepInvariantViolation(u32, u16, &'static str),
+    #[error("Client (id: {0}) has an account in both engines being merged")]
+    MergeConflict(u16),
+    #[error("Transaction (id: {0}) is in both engines being merged")]
+    TxMergeConflict(u32),
+    #[error("Transaction (id: {1}) has an unknown type {0:?}")]
+    UnknownTxType(String, u32),
+    #[error("Transaction (id: {0}) rejected while the engine is paused")]
+    EnginePaused(u32),
+    #[error("Record on line {0} is malformed: {1}")]
+    MalformedRecord(u64, String),
+    #[error("Transaction (id: {0}) unexpectedly specifies an amount")]
+    UnexpectedAmount(u32),
+    #[error("Client (id: {0}) exceeded the daily withdrawal limit")]
+    DailyLimitExceeded(u16),
+    #[error("Funds of account (id: {0}) can't be expressed as whole minor units")]
+    MinorUnits(u16),
+    #[error("Transaction (id: {0}) reuses the id of an earlier transaction")]
+    IdReused(u32),
+    #[error(
+        "Amount of transaction (id: {0}) is too far from a 4 decimal place value to be snapped"
+    )]
+    ImpreciseAmount(u32),
+    #[error("Withdrawal (id: {0}) would leave a dust balance")]
+    DustBalance(u32),
+    #[error("Client (id: {0}) does not have an account")]
+    AccountDoesNotExist(u16),
+    #[error("Account (id: {0}) is not locked")]
+    AccountNotLocked(u16),
+    #[error("Processing aborted after {0} records failed")]
+    TooManyErrors(u64),
+    #[error(
+        "Transaction (id: {0}) is a withdrawal, which can't be disputed unless withdrawal disputes are enabled"
+    )]
+    WithdrawalDisputesDisabled(u32),
+    #[error("Checkpoint of input {0:?} does not match the input being processed")]
+    CheckpointMismatch(String),
+    #[error("Sum of the funds of all accounts overflows")]
+    SumOverflow,
+    #[error("Input file {0} is gzip-compressed, which is not implemented yet")]
+    UnsupportedInput(String),
+    #[error("Penalty rate {0} is negative")]
+    NegativePenaltyRate(Decimal),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum TxType {
+impl Error {
+    /// Name of the error variant, used to group errors in the [`ErrorReport`]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::AccountLocked(_) => "AccountLocked",
+            Error::MissingTxAmount(_) => "MissingTxAmount",
+            Error::NotEnoughFunds(_) => "NotEnoughFunds",
+            Error::TxDoesNotExist(_) => "TxDoesNotExist",
+            Error::TxNotUnderDispute(_) => "TxNotUnderDispute",
+            Error::TxAlreadyUnderDispute(_) => "TxAlreadyUnderDispute",
+            Error::TxInvalidAmount(_) => "TxInvalidAmount",
+            Error::OriginalTxNotDeposit(..) => "OriginalTxNotDeposit",
+            Error::ClientIdMismatch(..) => "ClientIdMismatch",
+            Error::TxSpecifiesAmount(_) => "TxSpecifiesAmount",
+            Error::OverDispute(_) => "OverDispute",
+            Error::InvalidTransferDest(_) => "InvalidTransferDest",
+            Error::AlreadyChargedBack(_) => "AlreadyChargedBack",
+            Error::AmountParse(_) => "AmountParse",
+            Error::TooManyOpenDisputes(_) => "TooManyOpenDisputes",
+            Error::TooManyAccounts(_) => "TooManyAccounts",
+            Error::Overflow(_) => "Overflow",
+            Error::InvariantViolation(..) => "InvariantViolation",
+            Error::StepInvariantViolation(..) => "StepInvariantViolation",
+            Error::MergeConflict(_) => "MergeConflict",
+            Error::TxMergeConflict(_) => "TxMergeConflict",
+            Error::UnknownTxType(..) => "UnknownTxType",
+            Error::EnginePaused(_) => "EnginePaused",
+            Error::MalformedRecord(..) => "MalformedRecord",
+            Error::UnexpectedAmount(_) => "UnexpectedAmount",
+            Error::ImpreciseAmount(_) => "ImpreciseAmount",
+            Error::DustBalance(_) => "DustBalance",
+            Error::AccountDoesNotExist(_) => "AccountDoesNotExist",
+            Error::AccountNotLocked(_) => "AccountNotLocked",
+            Error::TooManyErrors(_) => "TooManyErrors",
+            Error::WithdrawalDisputesDisabled(_) => "WithdrawalDisputesDisabled",
+            Error::CheckpointMismatch(_) => "CheckpointMismatch",
+            Error::SumOverflow => "SumOverflow",
+            Error::IdReused(_) => "IdReused",
+            Error::MinorUnits(_) => "MinorUnits",
+            Error::DailyLimitExceeded(_) => "DailyLimitExceeded",
+            Error::UnsupportedInput(_) => "UnsupportedInput",
+            Error::NegativePenaltyRate(_) => "NegativePenaltyRate",
+            Error::Io(_) => "Io",
+        }
+    }
+}
+
+// Serialized by name, see the `Display` impl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     ChargeBack,
+    Transfer,
+    /// Manual correction of the available funds, the amount may be negative
+    Adjustment,
+    /// Reversal of a deposit that debits the available funds without locking the account
+    Refund,
+    /// Administrative hold of part of the available funds, independent of disputes
+    Hold,
+    /// Release of funds held by [`TxType::Hold`]
+    Unhold,
+    /// Penalty charged by [`PaymentsEngine::accrue_penalties`] on the funds held by a dispute,
+    /// which only appears in the audit log and is rejected as input
+    Penalty,
+    /// Type not known to the engine, rejected with [`Error::UnknownTxType`]
+    Unknown(String),
 }
 
-#[derive(Debug, Deserialize)]
-struct Tx {
-    #[serde(rename = "tx")]
-    id: u32,
+impl Serialize for TxType {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Unknown types are accepted, so that they are reported with their tx id instead of as
+// malformed records
+impl<'de> Deserialize<'de> for TxType {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "deposit" => TxType::Deposit,
+            "withdrawal" => TxType::Withdrawal,
+            "dispute" => TxType::Dispute,
+            "resolve" => TxType::Resolve,
+            "chargeback" => TxType::ChargeBack,
+            "transfer" => TxType::Transfer,
+            "adjustment" => TxType::Adjustment,
+            "refund" => TxType::Refund,
+            "hold" => TxType::Hold,
+            "unhold" => TxType::Unhold,
+            "penalty" => TxType::Penalty,
+            _ => TxType::Unknown(name),
+        })
+    }
+}
+
+impl fmt::Display for TxType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TxType::Deposit => "deposit",
+            TxType::Withdrawal => "withdrawal",
+            TxType::Dispute => "dispute",
+            TxType::Resolve => "resolve",
+            TxType::ChargeBack => "chargeback",
+            TxType::Transfer => "transfer",
+            TxType::Adjustment => "adjustment",
+            TxType::Refund => "refund",
+            TxType::Hold => "hold",
+            TxType::Unhold => "unhold",
+            TxType::Penalty => "penalty",
+            TxType::Unknown(name) => name,
+        };
+        f.write_str(name)
+    }
+}
+
+// The fields are ordered as the columns of the input
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tx {
     #[serde(rename = "type")]
     ty: TxType,
     client: u16,
+    #[serde(rename = "tx")]
+    id: u32,
     amount: Option<Decimal>,
+    // Destination client of a transfer
+    dest: Option<u16>,
+    // Unix timestamp (in seconds) of the transaction, if provided by the input
+    timestamp: Option<u64>,
+    // Source of the transaction (e.g. `web` or `atm`), if provided by the input, see
+    // `EngineConfig::channels`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<String>,
+    // Position of the transaction in the order of arrival, see `EngineConfig::track_arrival`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arrival: Option<u64>,
+    // Unix timestamp up to which penalties were charged on the funds held by an open dispute,
+    // see `PaymentsEngine::accrue_penalties`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    penalized_until: Option<u64>,
+}
+
+// A transaction as read from the input, before its amount is parsed
+//
+// Parsing the amount separately lets us report an invalid amount along with the tx id.
+#[derive(Debug)]
+struct RawTx {
+    ty: TxType,
+    client: u16,
+    id: u32,
+    amount: Option<String>,
+    dest: Option<u16>,
+    timestamp: Option<u64>,
+    // Name of the client, see `Account::label`
+    client_name: Option<String>,
+    channel: Option<String>,
+}
+
+// A record as deserialized from the input, before its ids are decoded into a `RawTx`
+#[derive(Debug, Deserialize)]
+struct RawRecord {
+    #[serde(rename = "type")]
+    ty: TxType,
+    client: RawId,
+    #[serde(rename = "tx")]
+    id: RawId,
+    amount: Option<String>,
+    dest: Option<u16>,
+    timestamp: Option<u64>,
+    client_name: Option<String>,
+    channel: Option<String>,
+}
+
+impl RawRecord {
+    fn decode(self, ids: IdFormat) -> std::result::Result<RawTx, String> {
+        Ok(RawTx {
+            ty: self.ty,
+            client: self.client.decode(ids, "client")?,
+            id: self.id.decode(ids, "tx")?,
+            amount: self.amount,
+            dest: self.dest,
+            timestamp: self.timestamp,
+            client_name: self.client_name,
+            channel: self.channel,
+        })
+    }
+}
+
+// How the client and tx ids of the input are decoded: trimming the whitespace around them (see
+// `EngineConfig::normalize_ids`) and accepting hex and underscore-separated ids (see
+// `EngineConfig::lenient_ids`)
+#[derive(Debug, Clone, Copy)]
+struct IdFormat {
+    trim: bool,
+    lenient: bool,
+}
+
+impl From<&EngineConfig> for IdFormat {
+    fn from(config: &EngineConfig) -> Self {
+        Self {
+            trim: config.normalize_ids,
+            lenient: config.lenient_ids,
+        }
+    }
+}
+
+// A client or tx id, given either as a number or as text
+#[derive(Debug)]
+enum RawId {
+    Number(u64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for RawId {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(RawIdVisitor)
+    }
+}
+
+struct RawIdVisitor;
+
+impl serde::de::Visitor<'_> for RawIdVisitor {
+    type Value = RawId;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an id")
+    }
+
+    fn visit_u64<E>(self, id: u64) -> std::result::Result<RawId, E> {
+        Ok(RawId::Number(id))
+    }
+
+    // Rejected when decoded, along with the other text
+    fn visit_i64<E>(self, id: i64) -> std::result::Result<RawId, E> {
+        Ok(RawId::Text(id.to_string()))
+    }
+
+    fn visit_f64<E>(self, id: f64) -> std::result::Result<RawId, E> {
+        Ok(RawId::Text(id.to_string()))
+    }
+
+    fn visit_bool<E>(self, id: bool) -> std::result::Result<RawId, E> {
+        Ok(RawId::Text(id.to_string()))
+    }
+
+    fn visit_str<E>(self, id: &str) -> std::result::Result<RawId, E> {
+        Ok(RawId::Text(id.to_string()))
+    }
+
+    fn visit_string<E>(self, id: String) -> std::result::Result<RawId, E> {
+        Ok(RawId::Text(id))
+    }
+}
+
+impl RawId {
+    // Decode the id according to `ids`. Without any of its options, the ids must be plain
+    // numbers, which the CSV fields are deserialized as.
+    fn decode<T: TryFrom<u64>>(self, ids: IdFormat, field: &str) -> std::result::Result<T, String> {
+        let invalid = |id: &str| format!("invalid {} id {:?}", field, id);
+        let text = match self {
+            RawId::Number(id) => return T::try_from(id).map_err(|_| invalid(&id.to_string())),
+            RawId::Text(text) if ids.trim || ids.lenient => text,
+            RawId::Text(text) => return Err(invalid(&text)),
+        };
+        let id = if ids.trim { text.trim() } else { &text };
+        let value = match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+            Some(hex) if ids.lenient => u64::from_str_radix(hex, 16).ok(),
+            // Underscores may only separate digits
+            None if ids.lenient && id.split('_').all(|group| !group.is_empty()) => {
+                id.replace('_', "").parse().ok()
+            }
+            _ => id.parse().ok(),
+        };
+        value
+            .and_then(|value| T::try_from(value).ok())
+            .ok_or_else(|| invalid(id))
+    }
+}
+
+impl Tx {
+    pub fn new(ty: TxType, client: u16, id: u32, amount: Option<Decimal>) -> Self {
+        Self {
+            id,
+            ty,
+            client,
+            amount,
+            dest: None,
+            timestamp: None,
+            channel: None,
+            arrival: None,
+            penalized_until: None,
+        }
+    }
+
+    pub fn ty(&self) -> &TxType {
+        &self.ty
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+
+    /// Source of the transaction (e.g. `web` or `atm`), if provided by the input
+    pub fn channel(&self) -> Option<&str> {
+        self.channel.as_deref()
+    }
+
+    /// Position of the transaction in the order of arrival, if tracked
+    pub fn arrival_index(&self) -> Option<u64> {
+        self.arrival
+    }
+
+    /// Set the Unix timestamp (in seconds) of the transaction
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Create a transfer of `amount` from the `client` account to the `dest` account
+    pub fn transfer(client: u16, dest: u16, id: u32, amount: Decimal) -> Self {
+        Self {
+            dest: Some(dest),
+            ..Self::new(TxType::Transfer, client, id, Some(amount))
+        }
+    }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
     client: u16,
     available: Decimal,
     held: Decimal,
     locked: bool,
+    // Number of disputes currently open on the account
+    open_disputes: usize,
+    // Clock readings of the account creation and of the last transaction applied to it
+    first_seen_seq: u64,
+    last_activity_seq: u64,
+    label: Option<String>,
+    // Part of the held funds held by administrative holds rather than disputes
+    #[serde(default)]
+    admin_held: Decimal,
 }
 
 impl Account {
-    fn new(client: u16) -> Self {
+    fn new(client: u16, seq: u64) -> Self {
         Self {
             client,
             available: Decimal::ZERO,
             held: Decimal::ZERO,
             locked: false,
+            open_disputes: 0,
+            first_seen_seq: seq,
+            last_activity_seq: seq,
+            label: None,
+            admin_held: Decimal::ZERO,
         }
     }
 
+    /// Clock reading at which the account was created
+    pub fn first_seen_seq(&self) -> u64 {
+        self.first_seen_seq
+    }
+
+    /// Clock reading of the last transaction applied to the account
+    pub fn last_activity_seq(&self) -> u64 {
+        self.last_activity_seq
+    }
+
+    /// Name of the client, from the `client_name` column of its transactions
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
     fn total(&self) -> Decimal {
         self.available + self.held
     }
+
+    // The invariants of `PaymentsEngine::check_invariants` that only depend on the account,
+    // returning the one violated
+    fn check_balances(&self) -> std::result::Result<(), &'static str> {
+        if self.held < Decimal::ZERO {
+            return Err("held funds are negative");
+        }
+        if self.available.checked_add(self.held).is_none() {
+            return Err("total overflows");
+        }
+        if self.admin_held < Decimal::ZERO {
+            return Err("administrative holds are negative");
+        }
+        Ok(())
+    }
+
+    /// Part of the held funds held by administrative holds (see [`TxType::Hold`]) rather than
+    /// disputes
+    pub fn admin_held(&self) -> Decimal {
+        self.admin_held
+    }
+
+    // Add to the available funds, as long as they and the total stay representable
+    fn credit(&mut self, amount: Decimal, tx_id: u32) -> Result<()> {
+        let available = self
+            .available
+            .checked_add(amount)
+            .filter(|available| available.checked_add(self.held).is_some())
+            .ok_or(Error::Overflow(tx_id))?;
+        self.available = available;
+        Ok(())
+    }
+
+    // Take from the available funds, which must cover the amount
+    fn debit(&mut self, amount: Decimal, tx_id: u32) -> Result<()> {
+        if self.available < amount {
+            return Err(Error::NotEnoughFunds(tx_id));
+        }
+        self.available = self
+            .available
+            .checked_sub(amount)
+            .ok_or(Error::Overflow(tx_id))?;
+        Ok(())
+    }
+
+    // Move available funds to the administrative holds
+    fn hold(&mut self, amount: Decimal, tx_id: u32) -> Result<()> {
+        let mut updated = self.clone();
+        updated.debit(amount, tx_id)?;
+        updated.held = updated
+            .held
+            .checked_add(amount)
+            .ok_or(Error::Overflow(tx_id))?;
+        updated.admin_held += amount;
+        *self = updated;
+        Ok(())
+    }
+
+    // Move funds from the administrative holds back to the available funds
+    fn unhold(&mut self, amount: Decimal, tx_id: u32) -> Result<()> {
+        if self.admin_held < amount {
+            return Err(Error::NotEnoughFunds(tx_id));
+        }
+        let mut updated = self.clone();
+        updated.release(amount, tx_id)?;
+        updated.credit(amount, tx_id)?;
+        updated.admin_held -= amount;
+        *self = updated;
+        Ok(())
+    }
+
+    // Take from the held funds, which must cover the amount
+    fn release(&mut self, amount: Decimal, tx_id: u32) -> Result<()> {
+        if self.held < amount {
+            return Err(Error::NotEnoughFunds(tx_id));
+        }
+        self.held = self
+            .held
+            .checked_sub(amount)
+            .ok_or(Error::Overflow(tx_id))?;
+        Ok(())
+    }
 }
 
 // This struct is used to serialize the account summary to stdout
-#[derive(Debug, Serialize)]
-struct AccountSummary {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountSummary {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    /// Only printed with [`EngineConfig::with_label`]
+    #[serde(skip)]
+    pub label: Option<String>,
+}
+
+impl AccountSummary {
+    /// Express the funds as integers of minor units, with `places` decimal places per major
+    /// unit (e.g. cents for 2)
+    ///
+    /// Fails if a value has more decimal places or is out of range once converted.
+    pub fn to_minor_units(&self, places: u32) -> Result<Self> {
+        let err = || Error::MinorUnits(self.client);
+        let factor = 10i64
+            .checked_pow(places)
+            .map(Decimal::from)
+            .ok_or_else(err)?;
+        let convert = |value: Decimal| {
+            value
+                .checked_mul(factor)
+                .filter(|minor| minor.fract().is_zero())
+                .map(|minor| minor.trunc())
+                .ok_or_else(err)
+        };
+        Ok(Self {
+            available: convert(self.available)?,
+            held: convert(self.held)?,
+            total: convert(self.total)?,
+            label: self.label.clone(),
+            ..*self
+        })
+    }
+}
+
+// A record of the input: its original row, and the transaction deserialized from it or the line
+// and reason of the failure
+type InputRecord = (ByteRecord, std::result::Result<RawTx, (u64, String)>);
+
+// Reader of the CSV input, with the delimiter and trimmed whitespace of the config
+fn csv_reader<R: std::io::Read>(reader: R, config: &EngineConfig) -> csv::Reader<R> {
+    ReaderBuilder::new()
+        .trim(config.trim.into())
+        .delimiter(config.delimiter)
+        .flexible(true)
+        .from_reader(reader)
+}
+
+// Records deserialized from CSV along with their original row, or the line and reason of their
+// failure
+fn csv_records<R: std::io::Read>(
+    mut reader: csv::Reader<R>,
+    ids: IdFormat,
+) -> impl Iterator<Item = InputRecord> {
+    let headers = reader.byte_headers().cloned().unwrap_or_default();
+    let failure = |err: csv::Error| {
+        let line = err.position().map_or(0, |pos| pos.line());
+        Err((line, err.to_string()))
+    };
+    reader.into_byte_records().map(move |res| match res {
+        // The row is moved along with its record rather than copied
+        Ok(row) => {
+            let line = row.position().map_or(0, |pos| pos.line());
+            let raw = match row.deserialize::<RawRecord>(Some(&headers)) {
+                Ok(raw) => raw.decode(ids).map_err(|err| (line, err)),
+                Err(err) => failure(err),
+            };
+            (row, raw)
+        }
+        Err(err) => (ByteRecord::new(), failure(err)),
+    })
+}
+
+// Records parsed by a thread of `EngineConfig::parse_threads`: the number of bytes and lines
+// of their block of rows, and the records with their line numbers relative to the block
+type ParsedBlock = (u64, u64, Vec<InputRecord>);
+
+// Send the rows read from `reader` to the threads in turn, in blocks of whole rows of about
+// `block_size` bytes, until the input ends or the threads stop receiving them
+fn split_blocks<R: Read>(
+    mut reader: R,
+    threads: Vec<SyncSender<Vec<u8>>>,
+    block_size: usize,
+) -> std::io::Result<()> {
+    let mut block = Vec::new();
+    for thread in threads.iter().cycle() {
+        loop {
+            let len = block.len();
+            block.resize(len + block_size, 0);
+            let read = reader.read(&mut block[len..])?;
+            block.truncate(len + read);
+            if read == 0 {
+                // The last row may not end with a line break
+                if !block.is_empty() {
+                    let _ = thread.send(block);
+                }
+                return Ok(());
+            }
+            // A row longer than the block is completed by the next read
+            if let Some(end) = last_row_end(&block) {
+                let rest = block.split_off(end);
+                if thread.send(std::mem::replace(&mut block, rest)).is_err() {
+                    return Ok(());
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Offset following the last line break of `block` that is not within a quoted field, the
+// block starting at the start of a row
+fn last_row_end(block: &[u8]) -> Option<usize> {
+    let mut quoted = false;
+    let mut end = None;
+    for (i, byte) in block.iter().enumerate() {
+        match byte {
+            // Escaped quotes come in pairs, which leave the state unchanged
+            b'"' => quoted = !quoted,
+            b'\n' if !quoted => end = Some(i + 1),
+            _ => {}
+        }
+    }
+    end
+}
+
+// Records deserialized from JSON lines along with their original line if `keep_rows` is set,
+// or the line and reason of their failure
+fn jsonl_records<R: BufRead>(
+    reader: R,
+    ids: IdFormat,
+    keep_rows: bool,
+) -> impl Iterator<Item = InputRecord> {
+    reader
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i as u64 + 1, line))
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(move |(line_number, line)| {
+            let row = match &line {
+                Ok(line) if keep_rows => ByteRecord::from(vec![line.as_str()]),
+                _ => ByteRecord::new(),
+            };
+            let raw = line
+                .map_err(|err| err.to_string())
+                .and_then(|line| {
+                    serde_json::from_str::<RawRecord>(&line).map_err(|err| err.to_string())
+                })
+                .and_then(|raw| raw.decode(ids))
+                .map_err(|err| (line_number, err));
+            (row, raw)
+        })
+}
+
+// Move all the entries of `from` to `to`, replacing the entries with the same keys
+fn move_entries<K: Copy + 'static, V: 'static, S: Store<K, V>>(from: &mut S, to: &mut S) {
+    let keys: Vec<K> = from.keys().copied().collect();
+    for key in keys {
+        let value = from.remove(&key).expect("Entry exists");
+        to.insert(key, value);
+    }
+}
+
+fn validate_records<I>(records: I, decimal_comma: bool) -> Result<()>
+where
+    I: Iterator<Item = InputRecord>,
+{
+    for (_, res) in records {
+        let raw = res.map_err(|(line, err)| Error::MalformedRecord(line, err))?;
+        if let Some(amount) = &raw.amount {
+            parse_amount(amount, raw.id, decimal_comma)?;
+        }
+    }
+    Ok(())
+}
+
+// Parse an amount, with a comma as the decimal separator if `decimal_comma` is set
+fn parse_amount(amount: &str, id: u32, decimal_comma: bool) -> Result<Decimal> {
+    let amount = if decimal_comma {
+        Cow::Owned(amount.replace(',', "."))
+    } else {
+        Cow::Borrowed(amount)
+    };
+    let amount = amount.as_ref();
+    Decimal::from_str(amount)
+        .or_else(|_| Decimal::from_scientific(amount))
+        .map_err(|_| Error::AmountParse(id))
+}
+
+// Sums of the available, held and total funds of the summaries, failing if one overflows
+fn sum_summaries(summaries: &[AccountSummary]) -> Result<(Decimal, Decimal, Decimal)> {
+    summaries.iter().try_fold(
+        (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO),
+        |(available, held, total), summary| {
+            let add = |sum: Decimal, funds| sum.checked_add(funds).ok_or(Error::SumOverflow);
+            Ok((
+                add(available, summary.available)?,
+                add(held, summary.held)?,
+                add(total, summary.total)?,
+            ))
+        },
+    )
+}
+
+// Value of a column of the printed accounts
+fn summary_field(summary: &AccountSummary, column: OutputColumn) -> String {
+    match column {
+        OutputColumn::Client => summary.client.to_string(),
+        OutputColumn::Available => summary.available.to_string(),
+        OutputColumn::Held => summary.held.to_string(),
+        OutputColumn::Total => summary.total.to_string(),
+        OutputColumn::Locked => summary.locked.to_string(),
+        OutputColumn::Label => summary.label.clone().unwrap_or_default(),
+    }
+}
+
+// Account summary with the label column, printed with `EngineConfig::with_label`
+#[derive(Serialize)]
+struct LabeledAccountSummary<'a> {
     client: u16,
     available: Decimal,
     held: Decimal,
     total: Decimal,
     locked: bool,
+    label: &'a str,
+}
+
+impl<'a> From<&'a AccountSummary> for LabeledAccountSummary<'a> {
+    fn from(summary: &'a AccountSummary) -> Self {
+        Self {
+            client: summary.client,
+            available: summary.available,
+            held: summary.held,
+            total: summary.total,
+            locked: summary.locked,
+            label: summary.label.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+// Transaction written as a CSV row in the format of the input, see
+// `PaymentsEngine::write_transactions`. The channel is left out, so that the rows keep the
+// columns of the input format.
+#[derive(Serialize)]
+struct TxRow<'a> {
+    #[serde(rename = "type")]
+    ty: &'a TxType,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+    dest: Option<u16>,
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arrival: Option<u64>,
+}
+
+impl<'a> From<&'a Tx> for TxRow<'a> {
+    fn from(tx: &'a Tx) -> Self {
+        Self {
+            ty: &tx.ty,
+            client: tx.client,
+            tx: tx.id,
+            amount: tx.amount,
+            dest: tx.dest,
+            timestamp: tx.timestamp,
+            arrival: tx.arrival,
+        }
+    }
 }
 
 impl From<&Account> for AccountSummary {
@@ -93,251 +885,5350 @@ impl From<&Account> for AccountSummary {
             held: account.held.normalize(),
             total: account.total().normalize(),
             locked: account.locked,
+            label: account.label.clone(),
+        }
+    }
+}
+
+/// Format of an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Pick the format from the extension of `path`, falling back to CSV for unknown ones
+    ///
+    /// Decoding gzip-compressed inputs (`.csv.gz` and `.jsonl.gz`) is not implemented yet, they
+    /// fail with [`Error::UnsupportedInput`] instead of being parsed as CSV.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Err(Error::UnsupportedInput(path.display().to_string())),
+            Some("jsonl") => Ok(Self::Jsonl),
+            _ => Ok(Self::Csv),
         }
     }
 }
 
-pub struct PaymentsEngine {
+/// Format of the printed accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// As printed by [`PaymentsEngine::print_accounts`]
+    Csv,
+    /// A JSON array of [`AccountSummary`] objects, with the default columns
+    Json,
+    /// A single MessagePack array of maps with the columns printed in CSV, keyed by their name
+    /// in the header, and the funds as strings
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+/// An open dispute and the amount it holds on the client's account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputeInfo {
+    pub tx: u32,
+    pub client: u16,
+    pub held: Decimal,
+}
+
+/// Number of entries held in memory by the engine, see [`PaymentsEngine::memory_stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Retained deposits, withdrawals and other transactions that may be referenced later
+    pub txs: usize,
+    /// Open disputes
+    pub disputes: usize,
+    pub accounts: usize,
+    /// Ids of the transactions that were charged back
+    pub charged_back: usize,
+}
+
+/// Counters describing what happened to the records of the input
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Records that were applied to an account
+    pub processed: u64,
+    /// Records that were deserialized but rejected by the engine
+    pub rejected: u64,
+    /// Records that could not be deserialized (e.g. missing columns)
+    pub malformed: u64,
+    /// Records skipped because they were not part of the sample
+    pub sampled_out: u64,
+    /// Records for locked accounts that were skipped without reporting an error
+    pub skipped_locked: u64,
+    /// Adjustments that were applied (also counted as processed)
+    pub adjustments: u64,
+    /// Resolves and chargebacks of undisputed transactions that were ignored
+    pub stray_resolves: u64,
+    /// Amounts that lost precision when rounded to [`EngineConfig::amount_scale`]
+    pub rounding_events: u64,
+    /// Sum of the absolute differences between the parsed and the rounded amounts
+    pub rounding_residual: Decimal,
+    /// Disputes of transactions not seen yet, waiting for them to arrive, see
+    /// [`EngineConfig::buffer_orphan_disputes`]
+    #[serde(default)]
+    pub orphan_disputes: u64,
+    /// Records with a timestamp outside of the window of [`EngineConfig::from_timestamp`] and
+    /// [`EngineConfig::to_timestamp`] that were skipped
+    #[serde(default)]
+    pub outside_window: u64,
+    /// Records from a channel not selected by [`EngineConfig::channels`] that were skipped
+    #[serde(default)]
+    pub other_channel: u64,
+}
+
+impl Stats {
+    /// Number of records read from the input
+    pub fn records(&self) -> u64 {
+        self.processed
+            + self.rejected
+            + self.malformed
+            + self.sampled_out
+            + self.skipped_locked
+            + self.stray_resolves
+            + self.orphan_disputes
+            + self.outside_window
+            + self.other_channel
+    }
+}
+
+impl AddAssign<&Stats> for Stats {
+    fn add_assign(&mut self, other: &Stats) {
+        self.processed += other.processed;
+        self.rejected += other.rejected;
+        self.malformed += other.malformed;
+        self.sampled_out += other.sampled_out;
+        self.skipped_locked += other.skipped_locked;
+        self.adjustments += other.adjustments;
+        self.stray_resolves += other.stray_resolves;
+        self.rounding_events += other.rounding_events;
+        self.rounding_residual += other.rounding_residual;
+        self.orphan_disputes += other.orphan_disputes;
+        self.outside_window += other.outside_window;
+        self.other_channel += other.other_channel;
+    }
+}
+
+/// Change of an account by a transaction, see [`PaymentsEngine::with_audit_writer`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Clock reading of the transaction, see [`Account::last_activity_seq`]
+    pub seq: u64,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub ty: TxType,
+    pub amount: Option<Decimal>,
+    pub available_before: Decimal,
+    pub held_before: Decimal,
+    pub available_after: Decimal,
+    pub held_after: Decimal,
+}
+
+/// State of an engine, from which an engine can be restored to resume processing its input
+///
+/// The error report is not part of the snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    // Input file of the engine and its size in bytes, if it is a file, see
+    // `PaymentsEngine::resume_from`
+    #[serde(default)]
+    input_file: String,
+    #[serde(default)]
+    input_size: Option<u64>,
+    accounts: Vec<Account>,
+    txs: Vec<Tx>,
+    disputes: Vec<Tx>,
+    charged_back: Vec<u32>,
+    stats: Stats,
+    #[serde(default)]
+    next_arrival: u64,
+    #[serde(default)]
+    orphan_disputes: Vec<Tx>,
+    #[serde(default)]
+    daily_withdrawals: Vec<(u16, u64, Decimal)>,
+    #[serde(default)]
+    seen_ids: Vec<u32>,
+}
+
+impl EngineSnapshot {
+    pub fn write_json<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, self)
+    }
+
+    pub fn read_json<R: std::io::Read>(reader: R) -> serde_json::Result<Self> {
+        serde_json::from_reader(reader)
+    }
+}
+
+// Pre-images of the entries touched while a transaction group is open, used to roll the
+// group back if one of its transactions fails, and the audit records held back until the group
+// is committed
+#[derive(Default)]
+struct Journal {
+    audit: Vec<AuditRecord>,
+    accounts: HashMap<u16, Option<Account>>,
+    txs: HashMap<u32, Option<Tx>>,
+    disputes: HashMap<u32, Option<Tx>>,
+    charged_back: HashMap<u32, bool>,
+    seen_ids: HashMap<u32, bool>,
+    daily_withdrawals: HashMap<(u16, u64), Option<Decimal>>,
+}
+
+/// What happened to the records processed by [`run_str`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct RunReport {
+    pub stats: Stats,
+    pub errors: ErrorReport,
+    /// Error that aborted the processing, e.g. [`Error::TooManyErrors`], in which case the
+    /// accounts only reflect the records processed before it
+    pub aborted: Option<String>,
+}
+
+/// Process the CSV transactions of `input` and return the resulting accounts, sorted by client
+/// id, along with what happened to the records
+///
+/// Nothing is read from the filesystem or written to stdout, which makes it the simplest way to
+/// embed the engine. The malformed and rejected records are still reported on stderr.
+pub fn run_str(input: &str, config: EngineConfig) -> (Vec<AccountSummary>, RunReport) {
+    let mut engine = PaymentsEngine::with_config(String::new(), config);
+    engine.process_reader(std::io::Cursor::new(input));
+    let report = RunReport {
+        stats: engine.stats().clone(),
+        errors: engine.error_report().clone(),
+        aborted: engine.check_errors().err().map(|err| err.to_string()),
+    };
+    (engine.summaries(), report)
+}
+
+/// Engine applying transactions to client accounts
+///
+/// The accounts, transactions and open disputes are kept in [`HashMap`]s by default, or in the
+/// [`Store`]s given to [`with_stores`](Self::with_stores).
+pub struct PaymentsEngine<A = HashMap<u16, Account>, T = HashMap<u32, Tx>> {
     input_file: String,
     // Stores deposit and withdrawal transactions that have not been reversed
-    txs: HashMap<u32, Tx>,
-    // Stores open disputes
-    disputes: HashMap<u32, Tx>,
-    accounts: HashMap<u16, Account>,
+    txs: T,
+    // Stores open disputes, with the amount they currently hold
+    disputes: T,
+    // Ids of the transactions that were charged back
+    charged_back: HashSet<u32>,
+    // Ids of all the deposits and withdrawals applied, see `EngineConfig::forbid_id_reuse`
+    seen_ids: HashSet<u32>,
+    accounts: A,
+    stats: Stats,
+    error_report: ErrorReport,
+    journal: Option<Journal>,
+    clock: Box<dyn Clock>,
+    // Receives a human readable explanation of every processed record
+    explain: Option<Box<dyn Write>>,
+    // Receives an audit record of every account changed by a transaction
+    audit: Option<Box<dyn Write>>,
+    // Receives the progress of the input files being read, see `with_progress_writer`
+    progress: Option<Box<dyn Write>>,
+    // Receives the rejected records of the input, see `with_dead_letter_writer`
+    dead_letter: Option<csv::Writer<Box<dyn Write>>>,
+    // Whether the header has been written to the dead-letter writer
+    dead_letter_header_written: bool,
+    // Set asynchronously to stop processing the input, see `with_interrupt_flag`
+    interrupt: Option<&'static AtomicBool>,
+    config: EngineConfig,
+    // Whether `print_accounts` has already written the CSV header
+    header_written: bool,
+    // Number of records of the input still to be skipped, as they were processed before the
+    // engine was restored
+    resume_after: u64,
+    // Arrival index of the next transaction, see `EngineConfig::track_arrival`
+    next_arrival: u64,
+    // Whether money movements are rejected, see `pause`
+    paused: bool,
+    // Disputes waiting for the transaction they reference, by its id, see
+    // `EngineConfig::buffer_orphan_disputes`
+    orphan_disputes: HashMap<u32, Vec<Tx>>,
+    // Funds withdrawn by each client on each UTC day (days since the Unix epoch), see
+    // `EngineConfig::daily_withdrawal_limit`
+    daily_withdrawals: HashMap<(u16, u64), Decimal>,
+    // Invariant violation that aborted the processing, see
+    // `EngineConfig::check_invariants_each_step`
+    step_violation: Option<(u32, u16, &'static str)>,
 }
 
 impl PaymentsEngine {
     pub fn new(input_file: String) -> Self {
+        Self::with_config(input_file, EngineConfig::default())
+    }
+
+    pub fn with_config(input_file: String, config: EngineConfig) -> Self {
+        Self::with_stores(
+            input_file,
+            config,
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+        )
+    }
+
+    /// Start configuring an engine, see [`PaymentsEngineBuilder`]
+    pub fn builder() -> PaymentsEngineBuilder {
+        PaymentsEngineBuilder::new()
+    }
+}
+
+impl<A: Store<u16, Account>, T: Store<u32, Tx>> PaymentsEngine<A, T> {
+    /// Create an engine keeping its accounts, transactions and open disputes in the given
+    /// stores, which are expected to be empty
+    pub fn with_stores(
+        input_file: String,
+        config: EngineConfig,
+        accounts: A,
+        txs: T,
+        disputes: T,
+    ) -> Self {
         Self {
             input_file,
-            txs: HashMap::new(),
-            disputes: HashMap::new(),
-            accounts: HashMap::new(),
+            txs,
+            disputes,
+            charged_back: HashSet::new(),
+            seen_ids: HashSet::new(),
+            accounts,
+            stats: Stats::default(),
+            error_report: ErrorReport::default(),
+            journal: None,
+            clock: Box::new(SystemClock::default()),
+            explain: None,
+            audit: None,
+            progress: None,
+            dead_letter: None,
+            dead_letter_header_written: false,
+            interrupt: None,
+            config,
+            header_written: false,
+            resume_after: 0,
+            next_arrival: 0,
+            paused: false,
+            orphan_disputes: HashMap::new(),
+            daily_withdrawals: HashMap::new(),
+            step_violation: None,
         }
     }
 
-    /// Process the transactions in the input file
-    pub fn run(&mut self) {
-        let file = std::fs::File::open(&self.input_file).unwrap();
-        let mut reader = ReaderBuilder::new()
-            .trim(Trim::All)
-            .flexible(true)
-            .from_reader(file);
-        for res in reader.deserialize() {
-            match res {
-                Ok(tx) => {
-                    if let Err(err) = self.process_tx(tx) {
-                        eprintln!("Error: {}", err);
-                    }
-                }
-                Err(err) => {
-                    eprintln!(
-                        "Failed to deserialize record: {}. Record will be skipped.",
-                        err
-                    );
-                    continue;
-                }
-            }
-        }
+    /// Use the given clock to order account activity instead of the system clock
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
     }
 
-    /// Serialize the accounts to stdout as CSV
-    pub fn print_accounts<W: std::io::Write>(&self, writer: &mut W) {
-        let mut writer = csv::Writer::from_writer(writer);
-        for account in self.accounts.values() {
-            writer
-                .serialize(AccountSummary::from(account))
-                .expect("Failed to serialize accounts to stdout");
-        }
+    /// Narrate the effect of every processed record to `writer`
+    pub fn with_explain_writer<W: Write + 'static>(mut self, writer: W) -> Self {
+        self.explain = Some(Box::new(writer));
+        self
     }
 
-    fn process_tx(&mut self, tx: Tx) -> Result<()> {
-        let account = self
-            .accounts
-            .entry(tx.client)
-            .or_insert(Account::new(tx.client));
-        if account.locked {
-            // Do not accept further transactions for locked accounts
-            return Err(Error::AccountLocked(tx.client));
-        }
+    /// Stop processing the input once `flag` is set, e.g. by a signal handler
+    ///
+    /// The record being processed when the flag is set is completed, and the remaining records
+    /// and files are left unread, so that the accounts processed so far can be printed.
+    pub fn with_interrupt_flag(mut self, flag: &'static AtomicBool) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
 
-        match tx.ty {
-            TxType::Deposit | TxType::Withdrawal => {
-                let amount = tx.amount.ok_or(Error::MissingTxAmount(tx.id))?;
-                if amount <= Decimal::ZERO {
-                    return Err(Error::TxInvalidAmount(tx.id));
+    /// Whether the processing was stopped by the flag of
+    /// [`with_interrupt_flag`](Self::with_interrupt_flag)
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupt
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Append an [`AuditRecord`] to `writer` for every account changed by a transaction, as a
+    /// line of JSON
+    ///
+    /// The writer is flushed after every record. The records of a
+    /// [`transaction_group`](Self::transaction_group) are written once the whole group is
+    /// applied, and not at all if it is rolled back.
+    pub fn with_audit_writer<W: Write + 'static>(mut self, writer: W) -> Self {
+        self.audit = Some(Box::new(writer));
+        self
+    }
+
+    /// Print the share of each input file read so far to `writer`, on a line updated
+    /// periodically, e.g. to stderr for interactive runs
+    pub fn with_progress_writer<W: Write + 'static>(mut self, writer: W) -> Self {
+        self.progress = Some(Box::new(writer));
+        self
+    }
+
+    /// Write every rejected record of the input to `writer` as a CSV row, whether it failed to
+    /// deserialize or was rejected by the engine, so that it can be fixed and replayed
+    ///
+    /// The original fields of the row are followed by an `error` column with the reason of the
+    /// rejection, under the header of the first input with a rejected record. The rows of JSON
+    /// lines have the original line as their only field.
+    pub fn with_dead_letter_writer<W: Write + 'static>(mut self, writer: W) -> Self {
+        self.dead_letter = Some(csv::Writer::from_writer(Box::new(writer)));
+        self
+    }
+
+    /// Process the transactions in the input file
+    ///
+    /// If the input is a directory, every `*.csv` file it contains is processed in the
+    /// lexical order of the file names, and the other files are skipped. Once the whole input
+    /// is processed, the checkpoint at [`EngineConfig::checkpoint_path`] is removed, so that a
+    /// later run does not resume from it.
+    pub fn run(&mut self) -> Result<()> {
+        self.run_input()?;
+        if !self.is_interrupted() {
+            self.remove_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn run_input(&mut self) -> Result<()> {
+        let input = PathBuf::from(&self.input_file);
+        if !input.is_dir() {
+            return self.process_file(&input);
+        }
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&input)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "csv") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        for path in paths {
+            if self.is_interrupted() {
+                break;
+            }
+            self.process_file(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Process the transactions in the given file
+    ///
+    /// The input format is picked from the file extension, see [`InputFormat::from_path`].
+    /// With [`EngineConfig::all_or_nothing`], the whole file is validated first and rejected
+    /// without processing any record if one of them can't be parsed.
+    pub fn process_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let format = InputFormat::from_path(path)?;
+        if self.config.all_or_nothing {
+            let file = BufReader::new(std::fs::File::open(path)?);
+            self.validate_input(file, format)?;
+        }
+        let threads = self.config.parse_threads.get();
+        if format == InputFormat::Csv && threads > 1 {
+            self.process_csv_in_parallel(path, threads, PARSE_BLOCK_SIZE)?;
+            return self.check_errors();
+        }
+        let file = std::fs::File::open(path)?;
+        // Taken out of the engine while the input is read, as `audit` is in `audit_tx`
+        let mut progress = self.progress.take();
+        match progress.as_deref_mut() {
+            Some(writer) => {
+                let total = file.metadata()?.len();
+                self.process_input(ProgressReader::new(file, total, writer), format);
+            }
+            None => self.process_input(file, format),
+        }
+        self.progress = progress;
+        self.check_errors()
+    }
+
+    // Process the CSV file at `path`, parsing its blocks of rows with `threads` threads while
+    // the records are applied in their original order
+    //
+    // The blocks are dealt to the threads in turn, and at most two blocks per thread are in
+    // flight, so the memory used does not depend on the size of the file.
+    fn process_csv_in_parallel(
+        &mut self,
+        path: &Path,
+        threads: usize,
+        block_size: usize,
+    ) -> std::io::Result<()> {
+        let file = std::fs::File::open(path)?;
+        let total = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = Vec::new();
+        reader.read_until(b'\n', &mut header)?;
+        let headers = csv_reader(header.as_slice(), &self.config)
+            .byte_headers()
+            .cloned()
+            .unwrap_or_default();
+        let config = self.config.clone();
+        let ids = IdFormat::from(&config);
+        // Taken out of the engine while the input is read, as in `process_file`
+        let mut progress_writer = self.progress.take();
+        let mut progress = progress_writer
+            .as_deref_mut()
+            .map(|writer| Progress::new(total, writer));
+        if let Some(progress) = progress.as_mut() {
+            progress.advance(header.len() as u64);
+        }
+        let res = std::thread::scope(|scope| {
+            let mut inputs = Vec::new();
+            let mut outputs = Vec::new();
+            for _ in 0..threads {
+                let (block_sender, blocks) = sync_channel::<Vec<u8>>(1);
+                let (parsed_sender, parsed) = sync_channel::<ParsedBlock>(1);
+                let (header, config) = (&header, &config);
+                scope.spawn(move || {
+                    for block in blocks {
+                        let lines = block.iter().filter(|&&byte| byte == b'\n').count();
+                        let reader = csv_reader(header.chain(block.as_slice()), config);
+                        let records = csv_records(reader, ids).collect();
+                        let parsed = (block.len() as u64, lines as u64, records);
+                        // The records are no longer received once the processing stops
+                        if parsed_sender.send(parsed).is_err() {
+                            break;
+                        }
+                    }
+                });
+                inputs.push(block_sender);
+                outputs.push(parsed);
+            }
+            let splitter = scope.spawn(move || split_blocks(reader, inputs, block_size));
+
+            // The blocks are received from the threads in the order they were dealt, and the
+            // line numbers made absolute, the header being the first line
+            let mut lines_before = 0;
+            let records = (0..)
+                .map_while(move |i| outputs[i % threads].recv().ok())
+                .flat_map(move |(len, lines, records)| {
+                    if let Some(progress) = progress.as_mut() {
+                        progress.advance(len);
+                    }
+                    let offset = lines_before;
+                    lines_before += lines;
+                    records.into_iter().map(move |(row, raw)| {
+                        (row, raw.map_err(|(line, err)| (line + offset, err)))
+                    })
+                });
+            self.process_records(&headers, records);
+            splitter.join().expect("Reading thread panicked")
+        });
+        self.progress = progress_writer;
+        res
+    }
+
+    // Process the input read from `reader` in the given format, buffered according to
+    // `EngineConfig::read_buffer_bytes`
+    fn process_input<R: Read>(&mut self, reader: R, format: InputFormat) {
+        let capacity = self.config.read_buffer_bytes;
+        match (format, capacity) {
+            (InputFormat::Csv, Some(capacity)) => {
+                self.process_reader(BufReader::with_capacity(capacity, reader))
+            }
+            (InputFormat::Csv, None) => self.process_reader(reader),
+            (InputFormat::Jsonl, Some(capacity)) => {
+                self.process_jsonl_reader(BufReader::with_capacity(capacity, reader))
+            }
+            (InputFormat::Jsonl, None) => self.process_jsonl_reader(BufReader::new(reader)),
+        }
+    }
+
+    // Whether more records failed than allowed by `EngineConfig::max_errors`
+    fn too_many_errors(&self) -> bool {
+        self.config
+            .max_errors
+            .is_some_and(|max| self.stats.rejected + self.stats.malformed > max)
+    }
+
+    // Fail with `Error::TooManyErrors` if the processing was aborted by `too_many_errors`, or
+    // with the `Error::StepInvariantViolation` that aborted it
+    fn check_errors(&self) -> Result<()> {
+        if let Some((id, client, invariant)) = self.step_violation {
+            return Err(Error::StepInvariantViolation(id, client, invariant));
+        }
+        if self.too_many_errors() {
+            return Err(Error::TooManyErrors(
+                self.stats.rejected + self.stats.malformed,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Process the CSV transactions read from `reader`
+    ///
+    /// Extra trailing columns are ignored, and the optional trailing columns (e.g. the amount
+    /// of a dispute) may be left out. Rows missing a required column (`type`, `client` or `tx`)
+    /// are skipped and counted as malformed. Whitespace is trimmed according to
+    /// [`EngineConfig::trim`] and [`EngineConfig::normalize_ids`].
+    pub fn process_reader<R: std::io::Read>(&mut self, reader: R) {
+        let mut reader = csv_reader(reader, &self.config);
+        let headers = reader.byte_headers().cloned().unwrap_or_default();
+        let ids = IdFormat::from(&self.config);
+        self.process_records(&headers, csv_records(reader, ids));
+    }
+
+    /// Process the transactions read from `reader`, one JSON object per line
+    ///
+    /// Blank lines are ignored. Amounts are given as strings, as JSON numbers are floats. With
+    /// [`EngineConfig::normalize_ids`], the ids may also be given as padded strings.
+    pub fn process_jsonl_reader<R: BufRead>(&mut self, reader: R) {
+        // The dead-letter rows are the original lines, only kept if they are written
+        let headers = ByteRecord::from(vec!["record"]);
+        let ids = IdFormat::from(&self.config);
+        let keep_rows = self.dead_letter.is_some();
+        self.process_records(&headers, jsonl_records(reader, ids, keep_rows));
+    }
+
+    // Check that every record of the input can be parsed, see `EngineConfig::all_or_nothing`
+    fn validate_input<R: BufRead>(&self, reader: R, format: InputFormat) -> Result<()> {
+        let (ids, decimal_comma) = (IdFormat::from(&self.config), self.config.decimal_comma);
+        match format {
+            InputFormat::Csv => validate_records(
+                csv_records(csv_reader(reader, &self.config), ids),
+                decimal_comma,
+            ),
+            InputFormat::Jsonl => {
+                validate_records(jsonl_records(reader, ids, false), decimal_comma)
+            }
+        }
+    }
+
+    // Second phase of the parsing, once the record has been split into its columns
+    fn parse_tx(&mut self, raw: RawTx) -> Result<Tx> {
+        let mut amount = raw
+            .amount
+            .map(|amount| parse_amount(&amount, raw.id, self.config.decimal_comma))
+            .transpose()?;
+        if let (Some(epsilon), Some(parsed)) = (self.config.snap_epsilon, amount) {
+            let snapped = parsed.round_dp(SNAP_SCALE);
+            if (parsed - snapped).abs() > epsilon {
+                return Err(Error::ImpreciseAmount(raw.id));
+            }
+            amount = Some(snapped);
+        }
+        if let (Some(scale), Some(parsed)) = (self.config.amount_scale, amount) {
+            let rounded = parsed.round_dp(scale);
+            if rounded != parsed {
+                self.stats.rounding_events += 1;
+                self.stats.rounding_residual += (parsed - rounded).abs();
+                if self.config.warn_on_rounding {
+                    eprintln!(
+                        "Warning: amount {} of transaction (id: {}) rounded to {}",
+                        parsed, raw.id, rounded
+                    );
+                }
+                amount = Some(rounded);
+            }
+        }
+        Ok(Tx {
+            ty: raw.ty,
+            client: raw.client,
+            id: raw.id,
+            amount,
+            dest: raw.dest,
+            timestamp: raw.timestamp,
+            channel: raw.channel,
+            arrival: None,
+            penalized_until: None,
+        })
+    }
+
+    // Apply the deserialized records, skipping the ones that failed to deserialize
+    //
+    // The rejected records are written to the dead-letter writer, under `headers`.
+    fn process_records<I>(&mut self, headers: &ByteRecord, records: I)
+    where
+        I: Iterator<Item = InputRecord>,
+    {
+        let sample_rate = self.config.sample_rate.get();
+        if sample_rate > 1 {
+            eprintln!(
+                "Warning: only 1 in {} records is processed, the result is approximate",
+                sample_rate
+            );
+        }
+        let checkpoint_every = self
+            .config
+            .checkpoint_every
+            .map_or(0, |every| every.get() as u64);
+        let compact_every = self
+            .config
+            .compact_every
+            .map_or(0, |every| every.get() as u64);
+        for (i, (row, res)) in records.enumerate() {
+            if self.is_interrupted() || self.too_many_errors() || self.step_violation.is_some() {
+                break;
+            }
+            if self.resume_after > 0 {
+                self.resume_after -= 1;
+                continue;
+            }
+            // Taken before the record is processed, so that it covers the records read so far
+            let read = self.stats.records();
+            if checkpoint_every > 0 && read > 0 && read.is_multiple_of(checkpoint_every) {
+                if let Err(err) = self.write_checkpoint() {
+                    eprintln!("Warning: failed to write checkpoint: {}", err);
+                }
+            }
+            if compact_every > 0 && read > 0 && read.is_multiple_of(compact_every) {
+                self.compact();
+            }
+            if i % sample_rate != 0 {
+                self.stats.sampled_out += 1;
+                continue;
+            }
+            match res {
+                Ok(raw) => {
+                    let (ty, client, id) = (raw.ty.clone(), raw.client, raw.id);
+                    let label = raw.client_name.clone().filter(|name| !name.is_empty());
+                    // Only formatted if the records are explained, see `with_explain_writer`
+                    let subject = match &raw.amount {
+                        _ if self.explain.is_none() => String::new(),
+                        Some(amount) => format!("{} of {} for client {}", ty, amount, client),
+                        None => format!("{} of tx {} for client {}", ty, id, client),
+                    };
+                    if raw
+                        .timestamp
+                        .is_some_and(|timestamp| !self.in_window(timestamp))
+                    {
+                        self.stats.outside_window += 1;
+                        self.explain(format_args!(
+                            "Skipped {}: outside the time window.",
+                            subject
+                        ));
+                        continue;
+                    }
+                    if raw
+                        .channel
+                        .as_ref()
+                        .is_some_and(|channel| !self.is_selected_channel(channel))
+                    {
+                        self.stats.other_channel += 1;
+                        self.explain(format_args!("Skipped {}: channel not selected.", subject));
+                        continue;
+                    }
+                    let mut orphan = None;
+                    let res = self.parse_tx(raw).and_then(|tx| {
+                        if tx.ty == TxType::Dispute && self.config.buffer_orphan_disputes {
+                            orphan = Some(tx.clone());
+                        }
+                        self.process_tx(tx)
+                    });
+                    if let Some(label) = label {
+                        self.apply_label(client, label);
+                    }
+                    match res {
+                        Ok(()) => {
+                            self.stats.processed += 1;
+                            if ty == TxType::Adjustment {
+                                self.stats.adjustments += 1;
+                            }
+                            let account = self.accounts.get(&client).expect("Account exists");
+                            let (available, held) = (account.available, account.held);
+                            self.explain(format_args!(
+                                "Applied {}; available {}, held {}.",
+                                subject, available, held
+                            ));
+                            if let Some(disputes) = self.orphan_disputes.remove(&id) {
+                                self.retry_orphan_disputes(disputes);
+                            }
+                        }
+                        Err(err @ Error::TxDoesNotExist(_)) if orphan.is_some() => {
+                            let dispute = orphan.expect("Dispute is buffered");
+                            self.stats.orphan_disputes += 1;
+                            self.orphan_disputes.entry(id).or_default().push(dispute);
+                            self.explain(format_args!("Buffered {}: {}.", subject, err));
+                        }
+                        Err(err @ Error::AccountLocked(_))
+                            if self.config.locked_tx_behavior != LockedTxBehavior::Error =>
+                        {
+                            self.stats.skipped_locked += 1;
+                            self.explain(format_args!("Skipped {}: {}.", subject, err));
+                        }
+                        Err(err @ Error::TxNotUnderDispute(_))
+                            if self.config.ignore_stray_resolves =>
+                        {
+                            self.stats.stray_resolves += 1;
+                            self.explain(format_args!("Skipped {}: {}.", subject, err));
+                        }
+                        Err(Error::StepInvariantViolation(id, client, invariant)) => {
+                            // The transaction was applied, the engine state can't be trusted
+                            self.stats.processed += 1;
+                            self.step_violation = Some((id, client, invariant));
+                        }
+                        Err(err) => {
+                            self.stats.rejected += 1;
+                            self.error_report.record(&err, id);
+                            self.explain(format_args!("Rejected {}: {}.", subject, err));
+                            self.dead_letter(headers, &row, &err.to_string());
+                            eprintln!("Error: {}", err);
+                        }
+                    }
+                }
+                Err((line, err)) => {
+                    self.stats.malformed += 1;
+                    self.error_report.record_malformed();
+                    self.explain(format_args!(
+                        "Skipped malformed record on line {}: {}.",
+                        line, err
+                    ));
+                    self.dead_letter(headers, &row, &err);
+                    eprintln!(
+                        "Failed to deserialize record on line {}: {}. Record will be skipped.",
+                        line, err
+                    );
+                    continue;
+                }
+            }
+        }
+    }
+
+    // Whether the records of a channel are processed, see `EngineConfig::channels`
+    fn is_selected_channel(&self, channel: &str) -> bool {
+        self.config
+            .channels
+            .as_ref()
+            .is_none_or(|channels| channels.iter().any(|selected| selected == channel))
+    }
+
+    // Whether a timestamp is within the window of `EngineConfig::from_timestamp` and
+    // `EngineConfig::to_timestamp`
+    fn in_window(&self, timestamp: u64) -> bool {
+        self.config
+            .from_timestamp
+            .is_none_or(|from| timestamp >= from)
+            && self.config.to_timestamp.is_none_or(|to| timestamp < to)
+    }
+
+    // Write a rejected record to the dead-letter writer, if any, with the reason of its rejection
+    fn dead_letter(&mut self, headers: &ByteRecord, row: &ByteRecord, reason: &str) {
+        let Some(writer) = &mut self.dead_letter else {
+            return;
+        };
+        if !self.dead_letter_header_written {
+            writer
+                .write_record(headers.iter().chain([b"error".as_slice()]))
+                .expect("Failed to write dead-letter record");
+            self.dead_letter_header_written = true;
+        }
+        writer
+            .write_record(row.iter().chain([reason.as_bytes()]))
+            .and_then(|()| writer.flush().map_err(csv::Error::from))
+            .expect("Failed to write dead-letter record");
+    }
+
+    // Apply the disputes that arrived before the transaction they reference
+    fn retry_orphan_disputes(&mut self, disputes: Vec<Tx>) {
+        for dispute in disputes {
+            self.stats.orphan_disputes -= 1;
+            let (client, id) = (dispute.client, dispute.id);
+            match self.process_tx(dispute) {
+                Ok(()) => {
+                    self.stats.processed += 1;
+                    self.explain(format_args!(
+                        "Applied buffered dispute of tx {} for client {}.",
+                        id, client
+                    ));
+                }
+                Err(err) => {
+                    self.stats.rejected += 1;
+                    self.error_report.record(&err, id);
+                    self.explain(format_args!(
+                        "Rejected buffered dispute of tx {} for client {}: {}.",
+                        id, client, err
+                    ));
+                    eprintln!("Error: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Disputes still waiting for the transaction they reference, sorted by transaction id
+    ///
+    /// These are only buffered with [`EngineConfig::buffer_orphan_disputes`].
+    pub fn orphan_disputes(&self) -> Vec<&Tx> {
+        let mut disputes: Vec<_> = self.orphan_disputes.values().flatten().collect();
+        disputes.sort_by_key(|dispute| dispute.id);
+        disputes
+    }
+
+    // Record the name of a client seen in the input, if it has an account
+    fn apply_label(&mut self, client: u16, label: String) {
+        let Some(account) = self.accounts.get_mut(&client) else {
+            return;
+        };
+        if account.label.is_none() || self.config.label_policy == LabelPolicy::Latest {
+            account.label = Some(label);
+        }
+    }
+
+    // Write a snapshot to the checkpoint path, replacing the previous one atomically
+    fn write_checkpoint(&self) -> std::io::Result<()> {
+        let Some(path) = &self.config.checkpoint_path else {
+            return Ok(());
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(std::fs::File::create(&tmp_path)?);
+        self.snapshot().write_json(&mut file)?;
+        file.flush()?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    fn remove_checkpoint(&self) -> std::io::Result<()> {
+        let Some(path) = &self.config.checkpoint_path else {
+            return Ok(());
+        };
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    // Narrate the effect of a record if the explain mode is enabled
+    fn explain(&mut self, explanation: std::fmt::Arguments) {
+        if let Some(writer) = self.explain.as_mut() {
+            writeln!(writer, "{}", explanation).expect("Failed to write explanation");
+        }
+    }
+
+    /// Capture the state of the engine, see [`EngineSnapshot`]
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let mut accounts: Vec<_> = self.accounts.values().cloned().collect();
+        accounts.sort_by_key(|account| account.client);
+        let mut txs: Vec<_> = self.txs.values().cloned().collect();
+        txs.sort_by_key(|tx| tx.id);
+        let mut disputes: Vec<_> = self.disputes.values().cloned().collect();
+        disputes.sort_by_key(|dispute| dispute.id);
+        let mut charged_back: Vec<_> = self.charged_back.iter().copied().collect();
+        charged_back.sort();
+        let mut seen_ids: Vec<_> = self.seen_ids.iter().copied().collect();
+        seen_ids.sort();
+        let mut orphan_disputes: Vec<_> =
+            self.orphan_disputes.values().flatten().cloned().collect();
+        orphan_disputes.sort_by_key(|dispute| dispute.id);
+        let mut daily_withdrawals: Vec<_> = self
+            .daily_withdrawals
+            .iter()
+            .map(|(&(client, day), &withdrawn)| (client, day, withdrawn))
+            .collect();
+        daily_withdrawals.sort_by_key(|&(client, day, _)| (client, day));
+        EngineSnapshot {
+            input_file: self.input_file.clone(),
+            input_size: self.input_size(),
+            accounts,
+            txs,
+            disputes,
+            charged_back,
+            stats: self.stats.clone(),
+            next_arrival: self.next_arrival,
+            orphan_disputes,
+            daily_withdrawals,
+            seen_ids,
+        }
+    }
+
+    // Size of the input file, if it is a file
+    fn input_size(&self) -> Option<u64> {
+        std::fs::metadata(&self.input_file)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+    }
+
+    /// Restore a snapshot taken while processing the same input, to resume processing it
+    ///
+    /// Fails with [`Error::CheckpointMismatch`] without changing the engine if the snapshot was
+    /// taken while processing another input file, or the input file has changed size since.
+    pub fn resume_from(&mut self, snapshot: EngineSnapshot) -> Result<()> {
+        if snapshot.input_file != self.input_file || snapshot.input_size != self.input_size() {
+            return Err(Error::CheckpointMismatch(snapshot.input_file));
+        }
+        self.restore(snapshot);
+        Ok(())
+    }
+
+    /// Replace the state of the engine with a snapshot
+    ///
+    /// The records already read when the snapshot was taken are skipped by the next inputs
+    /// that are processed, so that the same inputs can be resumed.
+    pub fn restore(&mut self, snapshot: EngineSnapshot) {
+        self.accounts.clear();
+        for account in snapshot.accounts {
+            self.accounts.insert(account.client, account);
+        }
+        self.txs.clear();
+        for tx in snapshot.txs {
+            self.txs.insert(tx.id, tx);
+        }
+        self.disputes.clear();
+        for dispute in snapshot.disputes {
+            self.disputes.insert(dispute.id, dispute);
+        }
+        self.charged_back = snapshot.charged_back.into_iter().collect();
+        self.seen_ids = snapshot.seen_ids.into_iter().collect();
+        self.next_arrival = snapshot.next_arrival;
+        self.daily_withdrawals = snapshot
+            .daily_withdrawals
+            .into_iter()
+            .map(|(client, day, withdrawn)| ((client, day), withdrawn))
+            .collect();
+        self.orphan_disputes = HashMap::new();
+        for dispute in snapshot.orphan_disputes {
+            self.orphan_disputes
+                .entry(dispute.id)
+                .or_default()
+                .push(dispute);
+        }
+        self.resume_after = snapshot.stats.records();
+        self.stats = snapshot.stats;
+    }
+
+    /// Summaries of the accounts whose funds or locked state differ from the baseline snapshot,
+    /// sorted by client id
+    ///
+    /// Accounts created since the baseline was taken are included.
+    pub fn changed_since(&self, baseline: &EngineSnapshot) -> Vec<AccountSummary> {
+        let baseline: HashMap<u16, &Account> = baseline
+            .accounts
+            .iter()
+            .map(|account| (account.client, account))
+            .collect();
+        self.summaries()
+            .into_iter()
+            .filter(|summary| {
+                baseline.get(&summary.client).is_none_or(|before| {
+                    before.available != summary.available
+                        || before.held != summary.held
+                        || before.locked != summary.locked
+                })
+            })
+            .collect()
+    }
+
+    /// Ids of all clients with an account, in increasing order
+    pub fn clients(&self) -> Vec<u16> {
+        let mut clients: Vec<_> = self.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Get the account of the given client
+    pub fn account(&self, client: u16) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Available funds of the given client
+    pub fn available(&self, client: u16) -> Option<Decimal> {
+        self.account(client).map(|account| account.available)
+    }
+
+    /// Held funds of the given client
+    pub fn held(&self, client: u16) -> Option<Decimal> {
+        self.account(client).map(|account| account.held)
+    }
+
+    /// Total funds of the given client
+    pub fn total(&self, client: u16) -> Option<Decimal> {
+        self.account(client).map(Account::total)
+    }
+
+    /// Whether the account of the given client is locked
+    pub fn is_locked(&self, client: u16) -> Option<bool> {
+        self.account(client).map(|account| account.locked)
+    }
+
+    /// Sum of the available funds of all accounts
+    pub fn total_available(&self) -> Result<Decimal> {
+        self.sum_funds(|account| account.available)
+    }
+
+    /// Sum of the held funds of all accounts
+    pub fn total_held(&self) -> Result<Decimal> {
+        self.sum_funds(|account| account.held)
+    }
+
+    /// Sum of the total funds of all accounts
+    pub fn grand_total(&self) -> Result<Decimal> {
+        self.total_available()?
+            .checked_add(self.total_held()?)
+            .ok_or(Error::SumOverflow)
+    }
+
+    fn sum_funds<F: Fn(&Account) -> Decimal>(&self, funds: F) -> Result<Decimal> {
+        self.accounts
+            .values()
+            .try_fold(Decimal::ZERO, |sum, account| {
+                sum.checked_add(funds(account))
+            })
+            .ok_or(Error::SumOverflow)
+    }
+
+    /// The options the engine was configured with
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Counters for the records processed so far
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Errors of the records processed so far, grouped by kind
+    pub fn error_report(&self) -> &ErrorReport {
+        &self.error_report
+    }
+
+    /// Serialize the retained deposits, withdrawals and transfers as CSV, sorted by id
+    pub fn emit_transactions<W: Write>(&self, writer: &mut W) {
+        self.write_transactions(writer, true);
+    }
+
+    // Write the retained transactions as CSV, sorted by id, with their arrival indices if
+    // `include_arrival` is set
+    fn write_transactions<W: Write>(&self, writer: &mut W, include_arrival: bool) {
+        let mut txs: Vec<_> = self.txs.values().collect();
+        txs.sort_by_key(|tx| tx.id);
+        let mut writer = csv::Writer::from_writer(writer);
+        for tx in txs {
+            let mut row = TxRow::from(tx);
+            if !include_arrival {
+                row.arrival = None;
+            }
+            writer
+                .serialize(row)
+                .expect("Failed to serialize transactions");
+        }
+        writer.flush().expect("Failed to flush transactions");
+    }
+
+    /// Summaries of all accounts, sorted by client id, as printed by `print_accounts`
+    ///
+    /// The totals are rounded to [`EngineConfig::total_scale`] decimal places, if set.
+    pub fn summaries(&self) -> Vec<AccountSummary> {
+        let mut summaries: Vec<_> = self.accounts.values().map(|a| self.summary(a)).collect();
+        summaries.sort_by_key(|summary| summary.client);
+        summaries
+    }
+
+    // Summary of an account, with the total rounded to `total_scale`
+    fn summary(&self, account: &Account) -> AccountSummary {
+        let mut summary = AccountSummary::from(account);
+        if let Some(scale) = self.config.total_scale {
+            summary.total = summary.total.round_dp(scale);
+            summary.total.rescale(scale);
+        }
+        summary
+    }
+
+    /// Summaries of all accounts, with the funds expressed as integers of minor units, see
+    /// [`AccountSummary::to_minor_units`]
+    pub fn summaries_in_minor_units(&self, places: u32) -> Result<Vec<AccountSummary>> {
+        self.summaries()
+            .iter()
+            .map(|summary| summary.to_minor_units(places))
+            .collect()
+    }
+
+    // Summaries of the accounts to print, only the negative ones with `negative_only`
+    fn printed_summaries(&self) -> Vec<AccountSummary> {
+        let mut summaries = match self.config.output_minor_units {
+            Some(places) => self
+                .summaries_in_minor_units(places)
+                .expect("Funds can be printed as minor units"),
+            None => self.summaries(),
+        };
+        if self.config.negative_only {
+            summaries.retain(|summary| summary.available < Decimal::ZERO);
+        }
+        summaries
+    }
+
+    /// Sums of the available, held and total funds of the accounts printed by
+    /// [`print_accounts`](Self::print_accounts), as in its [`EngineConfig::grand_total`] row
+    ///
+    /// Fails with [`Error::SumOverflow`] if a sum can't be represented.
+    pub fn printed_totals(&self) -> Result<(Decimal, Decimal, Decimal)> {
+        sum_summaries(&self.printed_summaries())
+    }
+
+    /// Clients with negative available funds, along with those funds, most negative first
+    ///
+    /// The available funds go negative when deposits that were already spent are disputed.
+    pub fn negative_accounts(&self) -> Vec<(u16, Decimal)> {
+        let mut accounts: Vec<_> = self
+            .accounts
+            .values()
+            .filter(|account| account.available < Decimal::ZERO)
+            .map(|account| (account.client, account.available))
+            .collect();
+        accounts.sort_by_key(|(client, available)| (*available, *client));
+        accounts
+    }
+
+    /// Serialize the accounts to stdout as CSV
+    ///
+    /// The accounts are sorted by client id, so the same input always gives the same output.
+    /// Each call writes a complete snapshot and flushes the writer, so it can be called
+    /// repeatedly by a long-running process. With [`EngineConfig::grand_total`], the accounts
+    /// are followed by a row with the client `*` and the sums of their funds. Only the
+    /// [`EngineConfig::columns`] are printed, if set, and only the accounts with negative
+    /// available funds with [`EngineConfig::negative_only`].
+    ///
+    /// # Panics
+    ///
+    /// With [`EngineConfig::output_minor_units`], panics if the funds of an account can't be
+    /// expressed as minor units, which can be checked beforehand with
+    /// [`summaries_in_minor_units`](Self::summaries_in_minor_units). With
+    /// [`EngineConfig::grand_total`], panics if the sums of the funds overflow, which can be
+    /// checked beforehand with [`printed_totals`](Self::printed_totals).
+    pub fn print_accounts<W: Write>(&mut self, writer: &mut W) {
+        let has_headers = !(self.config.omit_repeated_header && self.header_written);
+        self.write_accounts_csv(writer, has_headers);
+        self.header_written = true;
+    }
+
+    /// Print the accounts to several sinks at once, each in its own format
+    ///
+    /// The CSV sinks get the same output as [`print_accounts`](Self::print_accounts), so they
+    /// all get a header or none of them does.
+    pub fn print_accounts_to_sinks(&mut self, sinks: &mut [(OutputFormat, &mut dyn Write)]) {
+        let has_headers = !(self.config.omit_repeated_header && self.header_written);
+        for (format, writer) in sinks.iter_mut() {
+            match format {
+                OutputFormat::Csv => self.write_accounts_csv(writer, has_headers),
+                OutputFormat::Json => {
+                    serde_json::to_writer_pretty(&mut *writer, &self.printed_summaries())
+                        .expect("Failed to serialize accounts as JSON");
+                    writeln!(writer).expect("Failed to serialize accounts as JSON");
+                    writer.flush().expect("Failed to flush accounts");
+                }
+                #[cfg(feature = "msgpack")]
+                OutputFormat::MsgPack => {
+                    let columns: Vec<_> = self
+                        .printed_columns()
+                        .into_iter()
+                        .map(|column| (column, self.config.column_name(column)))
+                        .collect();
+                    msgpack::write_summaries(writer, &self.printed_summaries(), &columns)
+                        .and_then(|()| writer.flush())
+                        .expect("Failed to serialize accounts as MessagePack");
                 }
+            }
+        }
+        self.header_written = true;
+    }
+
+    /// Print the accounts as a JSON array, serializing them one at a time instead of collecting
+    /// their summaries first
+    ///
+    /// The accounts are printed in the same order and with the same options as with
+    /// [`print_accounts_to_sinks`](Self::print_accounts_to_sinks), but without indentation.
+    pub fn print_accounts_json_streaming<W: Write>(&self, writer: &mut W) {
+        writer
+            .write_all(b"[")
+            .expect("Failed to serialize accounts as JSON");
+        let mut first = true;
+        for client in self.clients() {
+            let mut summary = self.summary(self.accounts.get(&client).expect("Account exists"));
+            if let Some(places) = self.config.output_minor_units {
+                summary = summary
+                    .to_minor_units(places)
+                    .expect("Funds can be printed as minor units");
+            }
+            if self.config.negative_only && summary.available >= Decimal::ZERO {
+                continue;
+            }
+            if !first {
+                writer
+                    .write_all(b",")
+                    .expect("Failed to serialize accounts as JSON");
+            }
+            first = false;
+            serde_json::to_writer(&mut *writer, &summary)
+                .expect("Failed to serialize accounts as JSON");
+        }
+        writer
+            .write_all(b"]\n")
+            .expect("Failed to serialize accounts as JSON");
+        writer.flush().expect("Failed to flush accounts");
+    }
+
+    /// Write all the retained transactions as CSV, sorted by id, in the format of the input
+    ///
+    /// Their arrival indices are not written. The charged back deposits are not retained, see
+    /// [`history`](Self::history).
+    pub fn export_ledger<W: Write>(&self, writer: &mut W) {
+        self.write_transactions(writer, false);
+    }
+
+    fn write_accounts_csv<W: Write>(&self, writer: W, has_headers: bool) {
+        // The header of selected or renamed columns is written by hand
+        let custom_header = self.config.columns.is_some() || !self.config.column_names.is_empty();
+        let mut builder = WriterBuilder::new();
+        builder.has_headers(has_headers && !custom_header);
+        if self.config.crlf {
+            builder.terminator(Terminator::CRLF);
+        }
+        let mut writer = builder.from_writer(writer);
+        let columns = self.printed_columns();
+        if custom_header && has_headers {
+            writer
+                .write_record(
+                    columns
+                        .iter()
+                        .map(|column| self.config.column_name(*column)),
+                )
+                .expect("Failed to serialize accounts to stdout");
+        }
+        let summaries = self.printed_summaries();
+        let totals = self.config.grand_total.then(|| {
+            sum_summaries(&summaries).expect("Grand total of the accounts can be printed")
+        });
+        for summary in &summaries {
+            let res = match &self.config.columns {
+                Some(columns) => writer
+                    .write_record(columns.iter().map(|column| summary_field(summary, *column))),
+                None if self.config.with_label => {
+                    writer.serialize(LabeledAccountSummary::from(summary))
+                }
+                None => writer.serialize(summary),
+            };
+            res.expect("Failed to serialize accounts to stdout");
+        }
+        if let Some((available, held, total)) = totals {
+            let grand_total_field = |column| match column {
+                OutputColumn::Client => "*".to_string(),
+                OutputColumn::Available => available.normalize().to_string(),
+                OutputColumn::Held => held.normalize().to_string(),
+                OutputColumn::Total => total.normalize().to_string(),
+                OutputColumn::Locked | OutputColumn::Label => String::new(),
+            };
+            let record: Vec<_> = columns.into_iter().map(grand_total_field).collect();
+            writer
+                .write_record(&record)
+                .expect("Failed to serialize the grand total to stdout");
+        }
+        writer.flush().expect("Failed to flush accounts to stdout");
+    }
+
+    // The printed columns, in order: the selected ones or the default ones, with the label only
+    // if printed
+    fn printed_columns(&self) -> Vec<OutputColumn> {
+        match &self.config.columns {
+            Some(columns) => columns.clone(),
+            None => {
+                let len = OutputColumn::ALL.len() - usize::from(!self.config.with_label);
+                OutputColumn::ALL[..len].to_vec()
+            }
+        }
+    }
+
+    /// Charge a daily penalty on the funds held by open disputes
+    ///
+    /// For every open dispute with a timestamp that has been open for more than the penalty
+    /// threshold (see [`EngineConfig::penalty_threshold_days`]) at `as_of`, the client's
+    /// available funds are debited by `held * rate * days`, rounded to 4 decimal places, where
+    /// `days` is the number of whole days the dispute has been open, less the days charged by
+    /// the previous calls. Calling it again with the same `as_of` charges nothing. The
+    /// penalties are audited as [`TxType::Penalty`] of the disputed transactions.
+    ///
+    /// Fails with [`Error::NegativePenaltyRate`] if `rate` is negative. The penalties of every
+    /// client are charged together, and the clients whose penalties can't all be charged (e.g.
+    /// with [`Error::NotEnoughFunds`]) are returned with the error, their penalties being left
+    /// to a later call.
+    pub fn accrue_penalties(
+        &mut self,
+        rate: Decimal,
+        as_of: SystemTime,
+    ) -> Result<Vec<(u16, Error)>> {
+        if rate.is_sign_negative() {
+            return Err(Error::NegativePenaltyRate(rate));
+        }
+        let as_of = as_of
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let mut due = Vec::new();
+        for dispute in self.disputes.values() {
+            let Some(opened) = dispute.timestamp else {
+                continue;
+            };
+            let days_open = as_of.saturating_sub(opened) / SECONDS_PER_DAY;
+            if days_open <= self.config.penalty_threshold_days {
+                continue;
+            }
+            let days_charged = dispute
+                .penalized_until
+                .map_or(0, |until| until.saturating_sub(opened) / SECONDS_PER_DAY);
+            if days_open > days_charged {
+                let until = opened + days_open * SECONDS_PER_DAY;
+                due.push((dispute.client, dispute.id, days_open - days_charged, until));
+            }
+        }
+        // Charged by client, in the order of the disputed transactions
+        due.sort_unstable();
+        let mut failures = Vec::new();
+        for penalties in due.chunk_by(|a, b| a.0 == b.0) {
+            let client = penalties[0].0;
+            if let Err(err) = self.charge_penalties(client, penalties, rate) {
+                failures.push((client, err));
+            }
+        }
+        Ok(failures)
+    }
+
+    // Charge all the penalties of a client, given with the disputed transaction, the number of
+    // days charged and the time they are charged until, or none of them
+    fn charge_penalties(
+        &mut self,
+        client: u16,
+        penalties: &[(u16, u32, u64, u64)],
+        rate: Decimal,
+    ) -> Result<()> {
+        let mut account = self
+            .accounts
+            .get(&client)
+            .expect("Disputed account exists")
+            .clone();
+        let mut records = Vec::new();
+        for &(_, id, days, _) in penalties {
+            let dispute = self.disputes.get(&id).expect("Dispute is open");
+            let held = dispute.amount.expect("Open dispute has an amount");
+            let penalty = held
+                .checked_mul(rate)
+                .and_then(|penalty| penalty.checked_mul(Decimal::from(days)))
+                .ok_or(Error::Overflow(id))?
+                .round_dp(4);
+            let (available_before, held_before) = (account.available, account.held);
+            account.debit(penalty, id)?;
+            account.last_activity_seq = self.clock.now();
+            records.push(AuditRecord {
+                seq: account.last_activity_seq,
+                client,
+                tx: id,
+                ty: TxType::Penalty,
+                amount: Some(penalty),
+                available_before,
+                held_before,
+                available_after: account.available,
+                held_after: account.held,
+            });
+        }
+        self.accounts.insert(client, account);
+        for &(_, id, _, until) in penalties {
+            let dispute = self.disputes.get_mut(&id).expect("Dispute is open");
+            dispute.penalized_until = Some(until);
+        }
+        self.write_audit_records(&records);
+        Ok(())
+    }
+
+    /// Check that the state of every account is consistent
+    ///
+    /// The held funds must not be negative, the total must be representable and the number of
+    /// open disputes and the funds they hold must match the disputes map. The remaining held
+    /// funds must be the administrative holds.
+    pub fn check_invariants(&self) -> Result<()> {
+        let mut open_disputes: HashMap<u16, (usize, Decimal)> = HashMap::new();
+        for dispute in self.disputes.values() {
+            let (count, disputed) = open_disputes.entry(dispute.client).or_default();
+            *count += 1;
+            *disputed += dispute.amount.expect("Open dispute has an amount");
+        }
+        for account in self.accounts.values() {
+            account
+                .check_balances()
+                .map_err(|invariant| Error::InvariantViolation(account.client, invariant))?;
+            let (count, disputed) = open_disputes
+                .get(&account.client)
+                .copied()
+                .unwrap_or_default();
+            if account.open_disputes != count {
+                return Err(Error::InvariantViolation(
+                    account.client,
+                    "open dispute count does not match the open disputes",
+                ));
+            }
+            if account.held != disputed + account.admin_held {
+                return Err(Error::InvariantViolation(
+                    account.client,
+                    "held funds do not match the open disputes",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of entries held in memory
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            txs: self.txs.len(),
+            disputes: self.disputes.len(),
+            accounts: self.accounts.len(),
+            charged_back: self.charged_back.len(),
+        }
+    }
+
+    /// Drop the retained withdrawals to reclaim memory, unless they can be disputed (see
+    /// [`EngineConfig::withdrawal_disputes`]), returning the number of dropped transactions
+    ///
+    /// The dropped withdrawals are no longer part of the [`history`](Self::history) and
+    /// references to them are rejected with [`Error::TxDoesNotExist`].
+    pub fn compact(&mut self) -> usize {
+        if self.config.withdrawal_disputes {
+            return 0;
+        }
+        let withdrawals: Vec<_> = self
+            .txs
+            .iter()
+            .filter(|(_, tx)| tx.ty == TxType::Withdrawal)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &withdrawals {
+            self.txs.remove(id);
+        }
+        self.txs.shrink_to_fit();
+        withdrawals.len()
+    }
+
+    /// List the currently open disputes, sorted by transaction id
+    pub fn open_dispute_details(&self) -> Vec<DisputeInfo> {
+        let mut disputes: Vec<_> = self
+            .disputes
+            .values()
+            .map(|dispute| DisputeInfo {
+                tx: dispute.id,
+                client: dispute.client,
+                held: dispute.amount.expect("Open dispute has an amount"),
+            })
+            .collect();
+        disputes.sort_by_key(|dispute| dispute.tx);
+        disputes
+    }
+
+    /// Merge the state of an engine that processed a disjoint set of clients into this one
+    ///
+    /// Accounts, transactions, open disputes, stats and errors are combined. The merge fails
+    /// without modifying this engine if a client has an account in both engines, as their
+    /// transactions can't be interleaved without knowing their order, or if a transaction id is
+    /// used in both engines, as one transaction would replace the other.
+    pub fn merge(&mut self, mut other: Self) -> Result<()> {
+        let overlapping = other
+            .accounts
+            .keys()
+            .filter(|client| self.accounts.contains_key(client))
+            .min();
+        if let Some(client) = overlapping {
+            return Err(Error::MergeConflict(*client));
+        }
+        let overlapping = other
+            .txs
+            .keys()
+            .chain(other.disputes.keys())
+            .filter(|id| self.txs.contains_key(id) || self.disputes.contains_key(id))
+            .min();
+        if let Some(id) = overlapping {
+            return Err(Error::TxMergeConflict(*id));
+        }
+        move_entries(&mut other.accounts, &mut self.accounts);
+        move_entries(&mut other.txs, &mut self.txs);
+        move_entries(&mut other.disputes, &mut self.disputes);
+        self.charged_back.extend(other.charged_back);
+        self.seen_ids.extend(other.seen_ids);
+        self.daily_withdrawals.extend(other.daily_withdrawals);
+        for (id, disputes) in other.orphan_disputes {
+            self.orphan_disputes.entry(id).or_default().extend(disputes);
+        }
+        self.stats += &other.stats;
+        self.error_report.merge(&other.error_report);
+        Ok(())
+    }
+
+    /// Apply a group of transactions atomically
+    ///
+    /// If `f` returns an error, every mutation made by the transactions it processed is rolled
+    /// back and the error is returned. Nested groups are folded into the outermost one.
+    pub fn transaction_group<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        if self.journal.is_some() {
+            return f(self);
+        }
+
+        self.journal = Some(Journal::default());
+        let res = f(self);
+        let journal = self.journal.take().expect("Journal is open");
+        if res.is_ok() {
+            self.write_audit_records(&journal.audit);
+        } else {
+            for (client, account) in journal.accounts {
+                match account {
+                    Some(account) => self.accounts.insert(client, account),
+                    None => self.accounts.remove(&client),
+                };
+            }
+            for (id, tx) in journal.txs {
+                match tx {
+                    Some(tx) => self.txs.insert(id, tx),
+                    None => self.txs.remove(&id),
+                };
+            }
+            for (id, tx) in journal.disputes {
+                match tx {
+                    Some(tx) => self.disputes.insert(id, tx),
+                    None => self.disputes.remove(&id),
+                };
+            }
+            for (id, charged_back) in journal.charged_back {
+                if !charged_back {
+                    self.charged_back.remove(&id);
+                }
+            }
+            for (id, seen) in journal.seen_ids {
+                if !seen {
+                    self.seen_ids.remove(&id);
+                }
+            }
+            for (key, withdrawn) in journal.daily_withdrawals {
+                match withdrawn {
+                    Some(withdrawn) => self.daily_withdrawals.insert(key, withdrawn),
+                    None => self.daily_withdrawals.remove(&key),
+                };
+            }
+        }
+        res
+    }
+
+    /// Apply a single transaction to the engine
+    ///
+    /// With [`EngineConfig::track_arrival`], the transaction is given the next arrival index
+    /// before it is applied.
+    pub fn process_tx(&mut self, mut tx: Tx) -> Result<()> {
+        if self.audit.is_some() {
+            return self.audit_tx(tx);
+        }
+        let (id, client, dest) = (tx.id, tx.client, tx.dest);
+        if self.config.track_arrival {
+            tx.arrival = Some(self.next_arrival);
+        }
+        let res = self.apply_tx(tx);
+        if self.config.track_arrival && (res.is_ok() || self.config.count_rejected_arrivals) {
+            self.next_arrival += 1;
+        }
+        if res.is_ok() && self.config.check_invariants_each_step {
+            self.check_step_invariants(id, client, dest)?;
+        }
+        res
+    }
+
+    // Check the balances of the accounts touched by a transaction, see
+    // `EngineConfig::check_invariants_each_step`
+    fn check_step_invariants(&self, id: u32, client: u16, dest: Option<u16>) -> Result<()> {
+        for client in std::iter::once(client).chain(dest) {
+            if let Some(account) = self.accounts.get(&client) {
+                account
+                    .check_balances()
+                    .map_err(|invariant| Error::StepInvariantViolation(id, client, invariant))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the transactions in order, returning the result of each of them
+    ///
+    /// Like [`process_tx`](Self::process_tx), the rejected transactions are not counted in the
+    /// stats or the error report.
+    pub fn process_txs_detailed(&mut self, txs: Vec<Tx>) -> Vec<Result<()>> {
+        txs.into_iter().map(|tx| self.process_tx(tx)).collect()
+    }
+
+    // Apply a transaction, writing an audit record for every account it changed
+    fn audit_tx(&mut self, tx: Tx) -> Result<()> {
+        // The destination column is ignored by the other types of transactions
+        let dest = tx.dest.filter(|_| tx.ty == TxType::Transfer);
+        let clients: Vec<_> = std::iter::once(tx.client).chain(dest).collect();
+        let before: Vec<_> = clients
+            .iter()
+            .map(|client| {
+                self.accounts
+                    .get(client)
+                    .map_or((Decimal::ZERO, Decimal::ZERO), |account| {
+                        (account.available, account.held)
+                    })
+            })
+            .collect();
+        let (ty, id, amount) = (tx.ty.clone(), tx.id, tx.amount);
+        // Without the audit writer, `process_tx` applies the transaction as usual
+        let writer = self.audit.take().expect("Audit is enabled");
+        let res = self.process_tx(tx);
+        self.audit = Some(writer);
+        res?;
+        let records: Vec<_> = clients
+            .into_iter()
+            .zip(before)
+            .map(|(client, (available_before, held_before))| {
+                let account = self.accounts.get(&client).expect("Account exists");
+                AuditRecord {
+                    seq: account.last_activity_seq,
+                    client,
+                    tx: id,
+                    ty: ty.clone(),
+                    amount,
+                    available_before,
+                    held_before,
+                    available_after: account.available,
+                    held_after: account.held,
+                }
+            })
+            .collect();
+        self.write_audit_records(&records);
+        Ok(())
+    }
+
+    // Write audit records to the audit writer, if any, or hold them back until the open
+    // transaction group is committed
+    fn write_audit_records(&mut self, records: &[AuditRecord]) {
+        let Some(writer) = self.audit.as_mut() else {
+            return;
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.audit.extend_from_slice(records);
+            return;
+        }
+        for record in records {
+            let line = serde_json::to_string(record).expect("Audit record is serializable");
+            writeln!(writer, "{}", line)
+                .and_then(|()| writer.flush())
+                .expect("Failed to write audit record");
+        }
+    }
+
+    /// Compute the state the client's account would be in after applying `tx`, without
+    /// applying it
+    ///
+    /// Returns the error [`process_tx`](Self::process_tx) would return if the transaction is
+    /// invalid.
+    pub fn simulate(&self, tx: &Tx) -> Result<AccountSummary> {
+        // Run the transaction on a scratch engine holding copies of the entries it touches
+        let mut scratch = PaymentsEngine::with_config(String::new(), self.config.clone());
+        for client in std::iter::once(tx.client).chain(tx.dest) {
+            if let Some(account) = self.accounts.get(&client) {
+                scratch.accounts.insert(client, account.clone());
+            }
+        }
+        if let Some(max) = self.config.max_accounts {
+            // Leave room for as many new accounts as the engine has
+            let copied = scratch.accounts.len();
+            scratch.config.max_accounts = Some(max.saturating_sub(self.accounts.len()) + copied);
+        }
+        if let Some(original) = self.txs.get(&tx.id) {
+            scratch.txs.insert(tx.id, original.clone());
+        }
+        if let Some(dispute) = self.disputes.get(&tx.id) {
+            scratch.disputes.insert(tx.id, dispute.clone());
+        }
+        if self.charged_back.contains(&tx.id) {
+            scratch.charged_back.insert(tx.id);
+        }
+        if self.seen_ids.contains(&tx.id) {
+            scratch.seen_ids.insert(tx.id);
+        }
+        if let Some(day) = tx.timestamp.map(|timestamp| timestamp / SECONDS_PER_DAY) {
+            if let Some(withdrawn) = self.daily_withdrawals.get(&(tx.client, day)) {
+                scratch
+                    .daily_withdrawals
+                    .insert((tx.client, day), *withdrawn);
+            }
+        }
+        scratch.next_arrival = self.next_arrival;
+        scratch.paused = self.paused;
+
+        scratch.process_tx(tx.clone())?;
+        let account = scratch.accounts.get(&tx.client).expect("Account exists");
+        Ok(AccountSummary::from(account))
+    }
+
+    /// Reject every deposit, withdrawal, transfer, adjustment, refund and hold until
+    /// [`resume`](Self::resume) is called
+    ///
+    /// Disputes, resolves and chargebacks are still applied, unless
+    /// [`EngineConfig::pause_disputes`] is set.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Accept transactions again after [`pause`](Self::pause)
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Unlock the account of the given client, e.g. to recover it after a chargeback
+    ///
+    /// The funds of the account are left as they are.
+    pub fn unlock(&mut self, client: u16) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(Error::AccountDoesNotExist(client))?;
+        if !account.locked {
+            return Err(Error::AccountNotLocked(client));
+        }
+        account.locked = false;
+        Ok(())
+    }
+
+    /// Transactions of the given client retained by the engine, in their order of arrival
+    ///
+    /// The transactions are sorted by id if their arrival is not tracked.
+    pub fn history(&self, client: u16) -> Vec<&Tx> {
+        let mut txs: Vec<_> = self.txs.values().filter(|tx| tx.client == client).collect();
+        txs.sort_by_key(|tx| (tx.arrival, tx.id));
+        txs
+    }
+
+    /// Write a statement of the client's account for customer support
+    ///
+    /// The statement lists the transactions of [`history`](Self::history), one per line, and
+    /// ends with the resulting balance. Nothing is written for an unknown client.
+    pub fn statement<W: Write>(&self, client: u16, w: &mut W) {
+        let Some(account) = self.accounts.get(&client) else {
+            return;
+        };
+        let mut write = || -> std::io::Result<()> {
+            match &account.label {
+                Some(label) => writeln!(w, "Statement for client {} ({})", client, label)?,
+                None => writeln!(w, "Statement for client {}", client)?,
+            }
+            for tx in self.history(client) {
+                let amount = tx.amount.unwrap_or_default();
+                match tx.dest {
+                    Some(dest) => writeln!(
+                        w,
+                        "  tx {}: {} of {} to client {}",
+                        tx.id, tx.ty, amount, dest
+                    )?,
+                    None => writeln!(w, "  tx {}: {} of {}", tx.id, tx.ty, amount)?,
+                }
+            }
+            writeln!(
+                w,
+                "Balance: available {}, held {}, total {}{}",
+                account.available.normalize(),
+                account.held.normalize(),
+                account.total().normalize(),
+                if account.locked { ", locked" } else { "" }
+            )?;
+            w.flush()
+        };
+        write().expect("Failed to write statement");
+    }
+
+    fn apply_tx(&mut self, mut tx: Tx) -> Result<()> {
+        match tx.ty {
+            TxType::Unknown(name) => return Err(Error::UnknownTxType(name, tx.id)),
+            TxType::Penalty => return Err(Error::UnknownTxType(tx.ty.to_string(), tx.id)),
+            _ => {}
+        }
+        if self.paused {
+            let is_dispute = matches!(
+                tx.ty,
+                TxType::Dispute | TxType::Resolve | TxType::ChargeBack
+            );
+            if !is_dispute || self.config.pause_disputes {
+                return Err(Error::EnginePaused(tx.id));
+            }
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            // A transaction only ever touches its own tx id and the accounts of its client and
+            // of the transfer destination
+            for client in std::iter::once(tx.client).chain(tx.dest) {
+                journal
+                    .accounts
+                    .entry(client)
+                    .or_insert_with(|| self.accounts.get(&client).cloned());
+            }
+            journal
+                .txs
+                .entry(tx.id)
+                .or_insert_with(|| self.txs.get(&tx.id).cloned());
+            journal
+                .disputes
+                .entry(tx.id)
+                .or_insert_with(|| self.disputes.get(&tx.id).cloned());
+            journal
+                .charged_back
+                .entry(tx.id)
+                .or_insert_with(|| self.charged_back.contains(&tx.id));
+            journal
+                .seen_ids
+                .entry(tx.id)
+                .or_insert_with(|| self.seen_ids.contains(&tx.id));
+            if let Some(day) = tx.timestamp.map(|timestamp| timestamp / SECONDS_PER_DAY) {
+                journal
+                    .daily_withdrawals
+                    .entry((tx.client, day))
+                    .or_insert_with(|| self.daily_withdrawals.get(&(tx.client, day)).copied());
+            }
+        }
+
+        // Validate the transaction referenced by a dispute, resolve, chargeback or refund before
+        // touching any account, so that an invalid reference doesn't create a phantom account
+        let original = match tx.ty {
+            TxType::Dispute | TxType::Resolve | TxType::ChargeBack | TxType::Refund => {
+                Some(self.referenced_tx(&tx)?)
+            }
+            _ => None,
+        };
+
+        self.check_account_capacity(tx.client)?;
+        let now = self.clock.now();
+        let tx_client = tx.client;
+        if !self.accounts.contains_key(&tx.client) {
+            self.accounts
+                .insert(tx.client, Account::new(tx.client, now));
+        }
+        let account = self.accounts.get_mut(&tx.client).expect("Account exists");
+        // The disputes still open when an account is locked can be closed, otherwise their
+        // funds would stay held forever
+        let closes_dispute = matches!(tx.ty, TxType::Resolve | TxType::ChargeBack);
+        let replayed_dispute =
+            self.config.locked_tx_behavior == LockedTxBehavior::Replay && tx.ty == TxType::Dispute;
+        if account.locked && !closes_dispute && !replayed_dispute {
+            // Do not accept further transactions for locked accounts
+            return Err(Error::AccountLocked(tx.client));
+        }
+
+        match tx.ty {
+            TxType::Deposit | TxType::Withdrawal | TxType::Transfer => {
+                let mut amount = tx.amount.ok_or(Error::MissingTxAmount(tx.id))?;
+                if let Some(places) = self.config.round_input {
+                    amount = amount
+                        .round_dp_with_strategy(places, RoundingStrategy::MidpointAwayFromZero);
+                    tx.amount = Some(amount);
+                }
+                if tx.ty == TxType::Deposit
+                    && amount < Decimal::ZERO
+                    && self.config.negative_is_withdrawal
+                {
+                    // Stored as a withdrawal, so that disputes see the actual movement of funds
+                    amount = -amount;
+                    tx.ty = TxType::Withdrawal;
+                    tx.amount = Some(amount);
+                }
+                let is_deposit_or_withdrawal =
+                    matches!(tx.ty, TxType::Deposit | TxType::Withdrawal);
+                if self.config.forbid_id_reuse
+                    && is_deposit_or_withdrawal
+                    && self.seen_ids.contains(&tx.id)
+                {
+                    return Err(Error::IdReused(tx.id));
+                }
+                let heartbeat =
+                    amount.is_zero() && self.config.allow_zero_amount && is_deposit_or_withdrawal;
+                if amount <= Decimal::ZERO && !heartbeat {
+                    return Err(Error::TxInvalidAmount(tx.id));
+                }
+
+                match tx.ty {
+                    _ if heartbeat => {}
+                    TxType::Deposit => account.credit(amount, tx.id)?,
+                    TxType::Withdrawal => {
+                        // A remainder that overflows is not dust, `debit` rejects the
+                        // withdrawal
+                        let remaining = account.available.checked_sub(amount);
+                        let dust = self.config.dust_threshold.filter(|threshold| {
+                            remaining.is_some_and(|remaining| {
+                                remaining > Decimal::ZERO && remaining < *threshold
+                            })
+                        });
+                        if dust.is_some() {
+                            match self.config.dust_action {
+                                DustAction::Reject => return Err(Error::DustBalance(tx.id)),
+                                DustAction::Sweep => {
+                                    amount = account.available;
+                                    tx.amount = Some(amount);
+                                }
+                            }
+                        }
+                        let limited = self.config.daily_withdrawal_limit.zip(tx.timestamp);
+                        if let Some((limit, timestamp)) = limited {
+                            let key = (tx.client, timestamp / SECONDS_PER_DAY);
+                            let withdrawn = self
+                                .daily_withdrawals
+                                .get(&key)
+                                .copied()
+                                .unwrap_or_default();
+                            if withdrawn.checked_add(amount).is_none_or(|sum| sum > limit) {
+                                return Err(Error::DailyLimitExceeded(tx.client));
+                            }
+                            account.debit(amount, tx.id)?;
+                            self.daily_withdrawals.insert(key, withdrawn + amount);
+                        } else {
+                            account.debit(amount, tx.id)?;
+                        }
+                    }
+                    TxType::Transfer => {
+                        let dest = tx
+                            .dest
+                            .filter(|dest| *dest != tx.client)
+                            .ok_or(Error::InvalidTransferDest(tx.id))?;
+                        // Credit a copy of the destination first, so that neither account is
+                        // modified if one of them can't take the transfer
+                        let mut dest_account = match self.accounts.get(&dest) {
+                            Some(dest) if dest.locked => {
+                                return Err(Error::AccountLocked(dest.client));
+                            }
+                            Some(dest) => dest.clone(),
+                            None => {
+                                self.check_account_capacity(dest)?;
+                                Account::new(dest, now)
+                            }
+                        };
+                        dest_account.credit(amount, tx.id)?;
+                        dest_account.last_activity_seq = now;
+                        self.accounts
+                            .get_mut(&tx.client)
+                            .expect("Account exists")
+                            .debit(amount, tx.id)?;
+                        self.accounts.insert(dest, dest_account);
+                    }
+                    _ => unreachable!(),
+                }
+                if self.config.forbid_id_reuse && is_deposit_or_withdrawal {
+                    self.seen_ids.insert(tx.id);
+                }
+                if self.config.normalize_stored_amounts {
+                    tx.amount = tx.amount.map(|amount| amount.normalize());
+                }
+                self.txs.insert(tx.id, tx);
+            }
+            TxType::Adjustment => {
+                let amount = tx.amount.ok_or(Error::MissingTxAmount(tx.id))?;
+                if amount.is_zero() {
+                    return Err(Error::TxInvalidAmount(tx.id));
+                }
+                if amount > Decimal::ZERO {
+                    account.credit(amount, tx.id)?;
+                } else {
+                    account.debit(-amount, tx.id)?;
+                }
+                self.txs.insert(tx.id, tx);
+            }
+            TxType::Hold | TxType::Unhold => {
+                let amount = tx.amount.ok_or(Error::MissingTxAmount(tx.id))?;
+                if amount <= Decimal::ZERO {
+                    return Err(Error::TxInvalidAmount(tx.id));
+                }
+                if tx.ty == TxType::Hold {
+                    account.hold(amount, tx.id)?;
+                } else {
+                    account.unhold(amount, tx.id)?;
+                }
+                self.txs.insert(tx.id, tx);
+            }
+            TxType::Refund => {
+                let (_, amount) = original.expect("Referenced transaction was validated");
+                if self.disputes.contains_key(&tx.id) {
+                    return Err(Error::TxAlreadyUnderDispute(tx.id));
+                }
+                account.debit(amount, tx.id)?;
+                // The refund replaces the deposit, so it is kept for audit but can't be disputed
+                // or refunded again
+                self.txs.insert(tx.id, tx);
+            }
+            TxType::Unknown(_) | TxType::Penalty => unreachable!(),
+            TxType::Dispute | TxType::Resolve | TxType::ChargeBack => {
+                let (original_ty, amount) = original.expect("Referenced transaction was validated");
+                // The funds of a disputed withdrawal have already left the account, so they are
+                // held pending their return rather than moved out of the available funds
+                let is_withdrawal = original_ty == TxType::Withdrawal;
+
+                match tx.ty {
+                    TxType::Dispute => {
+                        let open_dispute = self.disputes.get(&tx.id);
+                        let already_disputed = open_dispute
+                            .map(|dispute| dispute.amount.expect("Open dispute has an amount"));
+                        // A partial dispute extends the open one, so it keeps its timestamp and
+                        // the penalties charged on it
+                        let timestamp = open_dispute
+                            .map_or(tx.timestamp, |dispute| dispute.timestamp.or(tx.timestamp));
+                        let penalized_until =
+                            open_dispute.and_then(|dispute| dispute.penalized_until);
+                        let disputed = match tx.amount {
+                            Some(partial) => {
+                                if partial <= Decimal::ZERO {
+                                    return Err(Error::TxInvalidAmount(tx.id));
+                                }
+                                // The open partial disputes may not hold more than was deposited
+                                if already_disputed
+                                    .unwrap_or_default()
+                                    .checked_add(partial)
+                                    .is_none_or(|disputed| disputed > amount)
+                                {
+                                    return Err(Error::OverDispute(tx.id));
+                                }
+                                partial
+                            }
+                            None => {
+                                if already_disputed.is_some() {
+                                    return Err(Error::TxAlreadyUnderDispute(tx.id));
+                                }
+                                amount
+                            }
+                        };
+                        let available = if is_withdrawal {
+                            account.available
+                        } else {
+                            account
+                                .available
+                                .checked_sub(disputed)
+                                .ok_or(Error::Overflow(tx.id))?
+                        };
+                        let held = account
+                            .held
+                            .checked_add(disputed)
+                            .filter(|held| available.checked_add(*held).is_some())
+                            .ok_or(Error::Overflow(tx.id))?;
+                        if already_disputed.is_none() {
+                            if self
+                                .config
+                                .max_open_disputes_per_client
+                                .is_some_and(|max| account.open_disputes >= max)
+                            {
+                                return Err(Error::TooManyOpenDisputes(tx.client));
+                            }
+                            account.open_disputes += 1;
+                        }
+                        account.available = available;
+                        account.held = held;
+                        let held = already_disputed.unwrap_or_default() + disputed;
+                        self.disputes.insert(
+                            tx.id,
+                            Tx {
+                                amount: Some(held),
+                                timestamp,
+                                penalized_until,
+                                ..tx
+                            },
+                        );
+                    }
+                    TxType::Resolve => {
+                        // Cancellation of a dispute
+                        let dispute = self
+                            .disputes
+                            .get(&tx.id)
+                            .ok_or(Error::TxNotUnderDispute(tx.id))?;
+                        let held = dispute.amount.expect("Open dispute has an amount");
+                        let mut updated = account.clone();
+                        updated.release(held, tx.id)?;
+                        if !is_withdrawal {
+                            updated.credit(held, tx.id)?;
+                        }
+                        updated.open_disputes -= 1;
+                        *account = updated;
+                        self.disputes.remove(&tx.id);
+                    }
+                    TxType::ChargeBack => {
+                        // Deposit reversal, or return of the withdrawn funds
+                        let dispute = self
+                            .disputes
+                            .get(&tx.id)
+                            .ok_or(Error::TxNotUnderDispute(tx.id))?;
+                        let held = dispute.amount.expect("Open dispute has an amount");
+                        let mut updated = account.clone();
+                        updated.release(held, tx.id)?;
+                        if is_withdrawal {
+                            updated.credit(held, tx.id)?;
+                        }
+                        updated.open_disputes -= 1;
+                        updated.locked = true;
+                        *account = updated;
+                        self.disputes.remove(&tx.id);
+                        self.txs.remove(&tx.id);
+                        self.charged_back.insert(tx.id);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+        if let Some(account) = self.accounts.get_mut(&tx_client) {
+            account.last_activity_seq = now;
+        }
+        Ok(())
+    }
+    // Fails if creating an account for the client would exceed the maximum number of accounts
+    fn check_account_capacity(&self, client: u16) -> Result<()> {
+        let at_capacity = self
+            .config
+            .max_accounts
+            .is_some_and(|max| self.accounts.len() >= max);
+        if at_capacity && !self.accounts.contains_key(&client) {
+            return Err(Error::TooManyAccounts(client));
+        }
+        Ok(())
+    }
+
+    // Type and amount of the deposit (or withdrawal, if they can be disputed) referenced by a
+    // dispute, resolve, chargeback or refund
+    fn referenced_tx(&self, tx: &Tx) -> Result<(TxType, Decimal)> {
+        if tx.amount.is_some()
+            && self.config.strict_dispute_rows
+            && matches!(
+                tx.ty,
+                TxType::Dispute | TxType::Resolve | TxType::ChargeBack
+            )
+        {
+            return Err(Error::UnexpectedAmount(tx.id));
+        }
+        // Only a dispute may specify an amount, in which case it is a partial dispute
+        if tx.amount.is_some() && !matches!(tx.ty, TxType::Dispute) {
+            return Err(Error::TxSpecifiesAmount(tx.ty.clone()));
+        }
+        // Always resolve the referenced id against the retained deposits and withdrawals,
+        // never against the open disputes, so a dispute can't reference another dispute
+        let original_tx = self.txs.get(&tx.id).ok_or_else(|| {
+            if self.charged_back.contains(&tx.id) {
+                Error::AlreadyChargedBack(tx.id)
+            } else {
+                Error::TxDoesNotExist(tx.id)
+            }
+        })?;
+        if tx.client != original_tx.client {
+            return Err(Error::ClientIdMismatch(tx.ty.clone(), tx.id));
+        }
+        let disputable = match original_tx.ty {
+            TxType::Deposit => true,
+            TxType::Withdrawal => self.config.withdrawal_disputes && tx.ty != TxType::Refund,
+            _ => false,
+        };
+        if !disputable && original_tx.ty == TxType::Withdrawal && tx.ty != TxType::Refund {
+            return Err(Error::WithdrawalDisputesDisabled(tx.id));
+        }
+        if !disputable {
+            return Err(Error::OriginalTxNotDeposit(tx.ty.clone(), tx.id));
+        }
+        let amount = original_tx
+            .amount
+            .expect("Deposit or withdrawal transaction has an amount");
+        Ok((original_tx.ty.clone(), amount))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    use csv::Trim;
+
+    use super::*;
+    use crate::clock::SequenceClock;
+    use crate::config::TrimPolicy;
+
+    #[test]
+    fn test_account_balance_helpers() {
+        let mut account = Account::new(1, 0);
+        account
+            .credit(Decimal::new(10, 0), 1)
+            .expect("Credit succeeds");
+        account
+            .debit(Decimal::new(10, 0), 2)
+            .expect("Debit of all funds succeeds");
+        assert_eq!(account.available, Decimal::ZERO);
+        assert!(matches!(
+            account.debit(Decimal::new(1, 4), 3),
+            Err(Error::NotEnoughFunds(3))
+        ));
+
+        account
+            .credit(Decimal::MAX, 4)
+            .expect("Credit up to the maximum succeeds");
+        assert!(matches!(
+            account.credit(Decimal::ONE, 5),
+            Err(Error::Overflow(5))
+        ));
+        assert_eq!(account.available, Decimal::MAX);
+
+        // The total must stay representable too
+        let mut account = Account::new(1, 0);
+        account.held = Decimal::ONE;
+        assert!(matches!(
+            account.credit(Decimal::MAX, 6),
+            Err(Error::Overflow(6))
+        ));
+        assert_eq!(account.available, Decimal::ZERO);
+
+        account
+            .release(Decimal::ONE, 7)
+            .expect("Release of all held funds succeeds");
+        assert!(matches!(
+            account.release(Decimal::ONE, 8),
+            Err(Error::NotEnoughFunds(8))
+        ));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_deposits_and_withdrawals() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::ZERO
+        );
+        assert_eq!(
+            engine.accounts.get(&2).unwrap().available,
+            Decimal::new(4950, 2)
+        );
+    }
+
+    #[test]
+    fn test_failed_withdrawal() {
+        let mut engine = PaymentsEngine::new("examples/failed_withdrawal.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(5000, 2)
+        );
+    }
+
+    #[test]
+    fn test_disputes() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // Client 1 dispute was resolved
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(10000, 2)
+        );
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").held,
+            Decimal::ZERO
+        );
+        assert!(!engine.accounts.get(&1).expect("Account exists").locked);
+        assert!(!engine.disputes.contains_key(&1));
+
+        // Client 2 dispute is still open
+        assert_eq!(
+            engine.accounts.get(&2).expect("Account exists").available,
+            Decimal::ZERO
+        );
+        assert_eq!(
+            engine.accounts.get(&2).expect("Account exists").held,
+            Decimal::new(10000, 2)
+        );
+        assert!(engine.disputes.contains_key(&2));
+
+        // Client 3 resolve ignored since no dispute opened
+        assert_eq!(
+            engine.accounts.get(&3).expect("Account exists").available,
+            Decimal::new(10000, 2)
+        );
+        assert_eq!(
+            engine.accounts.get(&3).expect("Account exists").held,
+            Decimal::ZERO
+        );
+        assert!(!engine.disputes.contains_key(&3));
+    }
+
+    #[test]
+    fn test_reversed_deposit() {
+        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // Deposit was reversed and the deposit following the chargeback was ignored
+        assert!(engine.accounts.get(&1).expect("Account exists").locked);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::ZERO
+        );
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").held,
+            Decimal::ZERO
+        );
+        assert_eq!(engine.txs.len(), 0);
+    }
+
+    #[test]
+    fn test_double_dispute() {
+        let mut engine = PaymentsEngine::new("examples/double_dispute.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // The second dispute is rejected and leaves the funds held by the first one
+        assert_eq!(engine.available(1), Some(Decimal::new(20, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::new(100, 0)));
+        assert_eq!(engine.is_locked(1), Some(false));
+        assert_eq!(
+            engine
+                .error_report()
+                .group("TxAlreadyUnderDispute")
+                .map(|g| g.count),
+            Some(1)
+        );
+        assert_eq!(engine.stats().rejected, 1);
+    }
+
+    #[test]
+    fn test_double_resolve() {
+        let mut engine = PaymentsEngine::new("examples/double_resolve.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // The second resolve is rejected and does not release the funds a second time
+        assert_eq!(engine.available(1), Some(Decimal::new(120, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::ZERO));
+        assert_eq!(engine.is_locked(1), Some(false));
+        assert_eq!(
+            engine
+                .error_report()
+                .group("TxNotUnderDispute")
+                .map(|g| g.count),
+            Some(1)
+        );
+        assert_eq!(engine.stats().rejected, 1);
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert!(matches!(
+            engine.unlock(2),
+            Err(Error::AccountDoesNotExist(2))
+        ));
+
+        engine.unlock(1).expect("Account is unlocked");
+        assert_eq!(engine.is_locked(1), Some(false));
+        assert!(matches!(engine.unlock(1), Err(Error::AccountNotLocked(1))));
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 3, Some(Decimal::new(50, 0))))
+            .expect("Deposit is applied");
+        assert_eq!(engine.available(1), Some(Decimal::new(50, 0)));
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let mut engine = PaymentsEngine::new("examples/whitespace.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(9000, 2)
+        );
+    }
+
+    #[test]
+    fn test_print_accounts() {
+        let mut engine = PaymentsEngine::new("examples/simple_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,available,held,total,locked\n1,100.1001,0,100.1001,false\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_deterministic() {
+        // Each engine hashes its accounts with its own random seed, so the iteration order of
+        // the accounts differs between runs and only the sorting keeps the output stable
+        let print = || {
+            let mut engine = PaymentsEngine::new("examples/many_clients.csv".to_string());
+            engine.run().expect("Input is processed");
+            let mut buf = Vec::new();
+            engine.print_accounts(&mut buf);
+            String::from_utf8(buf).unwrap()
+        };
+        let output = print();
+        for _ in 0..10 {
+            assert_eq!(print(), output);
+        }
+
+        let expected = "client,available,held,total,locked\n\
+            1,1,0,1,false\n\
+            2,0.5,0,0.5,false\n\
+            3,12.75,0,12.75,false\n\
+            5,42,0,42,false\n\
+            7,10,0,10,false\n\
+            9,3,0,3,false\n\
+            12,0,0,0,true\n";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_print_accounts_crlf() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/simple_deposit.csv".to_string(),
+            EngineConfig {
+                crlf: true,
+                grand_total: true,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,available,held,total,locked\r\n\
+            1,100.1001,0,100.1001,false\r\n\
+            *,100.1001,0,100.1001,\r\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_minor_units() {
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                output_minor_units: Some(2),
+                ..Default::default()
+            },
+        );
+        engine.process_reader("type,client,tx,amount\ndeposit,1,1,100.10\n".as_bytes());
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        let expected = "client,available,held,total,locked\n1,10010,0,10010,false\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+        engine.process_reader("type,client,tx,amount\ndeposit,2,2,0.001\n".as_bytes());
+        assert!(matches!(
+            engine.summaries_in_minor_units(2),
+            Err(Error::MinorUnits(2))
+        ));
+        assert_eq!(
+            engine.summaries_in_minor_units(3).unwrap()[1].available,
+            Decimal::ONE
+        );
+    }
+
+    #[test]
+    fn test_negative_accounts() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            withdrawal,1,2,80\n\
+            dispute,1,1,\n\
+            deposit,2,3,50\n\
+            deposit,3,4,10\n\
+            withdrawal,3,5,5\n\
+            dispute,3,4,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                negative_only: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(
+            engine.negative_accounts(),
+            vec![(1, Decimal::new(-80, 0)), (3, Decimal::new(-5, 0))]
+        );
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        let expected = "client,available,held,total,locked\n\
+            1,-80,100,20,false\n\
+            3,-5,10,5,false\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_omit_repeated_header() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/simple_deposit.csv".to_string(),
+            EngineConfig {
+                omit_repeated_header: true,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,available,held,total,locked\n1,100.1001,0,100.1001,false\n1,100.1001,0,100.1001,false\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_total_scale() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/simple_deposit.csv".to_string(),
+            EngineConfig {
+                total_scale: Some(2),
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,available,held,total,locked\n1,100.1001,0,100.10,false\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_grand_total() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/deposits_and_withdrawals.csv".to_string(),
+            EngineConfig {
+                grand_total: true,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,available,held,total,locked\n\
+            1,0,0,0,false\n\
+            2,49.5,0,49.5,false\n\
+            *,49.5,0,49.5,\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        assert_eq!(
+            engine.printed_totals().unwrap(),
+            (Decimal::new(495, 1), Decimal::ZERO, Decimal::new(495, 1))
+        );
+
+        // Sums that overflow are reported rather than printed
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,1,{}\ndeposit,2,2,{}\n",
+            Decimal::MAX,
+            Decimal::MAX
+        );
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                grand_total: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().processed, 2);
+        assert!(matches!(engine.printed_totals(), Err(Error::SumOverflow)));
+    }
+
+    #[test]
+    fn test_print_accounts_with_label() {
+        let input = "type,client,tx,amount,client_name\n\
+            deposit,1,1,10,Alice\n\
+            deposit,2,2,5,\n\
+            deposit,1,3,10,Alice Smith\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                with_label: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.summaries()[0].label.as_deref(), Some("Alice"));
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        let expected = "client,available,held,total,locked,label\n\
+            1,20,0,20,false,Alice\n\
+            2,5,0,5,false,\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                label_policy: LabelPolicy::Latest,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.label(), Some("Alice Smith"));
+    }
+
+    #[test]
+    fn test_trim_policy() {
+        let input = "type,client,tx,amount\ndeposit,1,1, 10\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().processed, 1);
+
+        // Without trimming, the padded amount is not a valid decimal
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                trim: TrimPolicy::None,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().processed, 0);
+        assert!(engine.error_report().group("AmountParse").is_some());
+    }
+
+    #[test]
+    fn test_lenient_ids() {
+        let input = "type,client,tx,amount\n\
+            deposit,0x1A,1_000,10\n\
+            deposit,0X1a,0x10,5\n\
+            deposit,1,1__0,1\n\
+            deposit,1,_10,1\n\
+            deposit,0x10000,1,1\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().malformed, 5);
+
+        let config = EngineConfig {
+            lenient_ids: true,
+            ..Default::default()
+        };
+        let mut engine = PaymentsEngine::with_config(String::new(), config);
+        engine.process_reader(input.as_bytes());
+        // Misplaced underscores and out of range ids are still malformed
+        assert_eq!(engine.stats().processed, 2);
+        assert_eq!(engine.stats().malformed, 3);
+        let ids: Vec<_> = engine.history(26).iter().map(|tx| tx.id()).collect();
+        assert_eq!(ids, vec![16, 1000]);
+        assert_eq!(engine.available(26), Some(Decimal::new(15, 0)));
+    }
+
+    #[test]
+    fn test_normalize_ids() {
+        let input = "type,client,tx,amount\n\
+            deposit,\" 1 \",\"  7\",10\n\
+            withdrawal,\"1\t\",8,4\n";
+        let config = EngineConfig {
+            trim: TrimPolicy::None,
+            ..Default::default()
+        };
+        let mut engine = PaymentsEngine::with_config(String::new(), config.clone());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().malformed, 2);
+
+        let config = EngineConfig {
+            normalize_ids: true,
+            ..config
+        };
+        let mut engine = PaymentsEngine::with_config(String::new(), config.clone());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().processed, 2);
+        assert_eq!(engine.available(1), Some(Decimal::new(6, 0)));
+        assert_eq!(engine.history(1)[0].id(), 7);
+
+        // JSON ids may be given as padded strings as well
+        let input = "{\"type\":\"deposit\",\"client\":\" 2 \",\"tx\":3,\"amount\":\"5\"}\n";
+        let mut engine = PaymentsEngine::with_config(String::new(), config);
+        engine.process_jsonl_reader(input.as_bytes());
+        assert_eq!(engine.available(2), Some(Decimal::new(5, 0)));
+    }
+
+    #[test]
+    fn test_print_accounts_columns() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/simple_deposit.csv".to_string(),
+            EngineConfig {
+                columns: Some(vec![OutputColumn::Client, OutputColumn::Total]),
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = "client,total\n1,100.1001\n";
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_print_accounts_renamed_columns() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+        let mut expected = Vec::new();
+        engine.print_accounts(&mut expected);
+
+        engine.config.column_names = vec![
+            (OutputColumn::Client, "account".to_string()),
+            (OutputColumn::Available, "avail".to_string()),
+            (OutputColumn::Held, "onhold".to_string()),
+            (OutputColumn::Total, "balance".to_string()),
+            (OutputColumn::Locked, "frozen".to_string()),
+        ];
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+
+        let expected = String::from_utf8(expected).unwrap();
+        let printed = String::from_utf8(buf).unwrap();
+        let (header, rows) = printed.split_once('\n').unwrap();
+        assert_eq!(header, "account,avail,onhold,balance,frozen");
+        assert_eq!(rows, expected.split_once('\n').unwrap().1);
+    }
+
+    #[test]
+    fn test_print_accounts_to_sinks() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        let mut csv_buf = Vec::new();
+        let mut json_buf = Vec::new();
+        engine.print_accounts_to_sinks(&mut [
+            (OutputFormat::Csv, &mut csv_buf),
+            (OutputFormat::Json, &mut json_buf),
+        ]);
+
+        let mut expected = Vec::new();
+        engine.print_accounts(&mut expected);
+        assert_eq!(csv_buf, expected);
+        let printed: Vec<AccountSummary> =
+            serde_json::from_slice(&json_buf).expect("Printed accounts are valid JSON");
+        assert_eq!(printed, engine.summaries());
+    }
+
+    #[test]
+    fn test_print_accounts_json_streaming() {
+        let input = "type,client,tx,amount\n\
+                     deposit,3,1,1.5\n\
+                     deposit,1,2,2\n\
+                     deposit,2,3,3\n\
+                     dispute,2,3,\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        let mut buf = Vec::new();
+        engine.print_accounts_json_streaming(&mut buf);
+
+        let printed: Vec<AccountSummary> =
+            serde_json::from_slice(&buf).expect("Printed accounts are valid JSON");
+        assert_eq!(printed.len(), 3);
+        assert_eq!(printed, engine.summaries());
+    }
+
+    #[test]
+    fn test_parse_threads() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-parse-threads-{}.csv",
+            std::process::id()
+        ));
+        let mut input = "type,client,tx,amount\n".to_string();
+        for id in 1..=500u32 {
+            let client = id % 7;
+            let record = match id % 5 {
+                0 => format!("dispute,{},{},\n", (id - 2) % 7, id - 2),
+                1 => format!("withdrawal,{},{},{}.5\n", client, id, id % 13),
+                2 => "deposit,x,1,1\n".to_string(),
+                _ => format!("deposit,{},{},{}.25\n", client, id, id % 11),
+            };
+            input.push_str(&record);
+        }
+        // A quoted field may span several lines, and a row several blocks
+        input.push_str("deposit,1,1000,\"\n1.5\"\n");
+        input.push_str(&format!("deposit,1,1001,{}1.5\n", " ".repeat(200)));
+        input.push_str("deposit,x,1002,1\n");
+        std::fs::write(&path, &input).expect("Input is written");
+
+        #[derive(Clone, Default)]
+        struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let mut expected = PaymentsEngine::new(String::new()).with_explain_writer(buf.clone());
+        expected.process_file(&path).expect("Input is processed");
+        let expected_explanation = buf.0.take();
+        assert!(String::from_utf8_lossy(&expected_explanation)
+            .ends_with("Skipped malformed record on line 505: invalid client id \"x\".\n"));
+        for (threads, block_size) in [(2, PARSE_BLOCK_SIZE), (3, 64), (8, 1)] {
+            let mut engine = PaymentsEngine::with_config(
+                String::new(),
+                EngineConfig {
+                    parse_threads: NonZeroUsize::new(threads).unwrap(),
+                    ..Default::default()
+                },
+            )
+            .with_explain_writer(buf.clone());
+            engine
+                .process_csv_in_parallel(&path, threads, block_size)
+                .expect("Input is processed");
+            assert_eq!(engine.summaries(), expected.summaries());
+            assert_eq!(engine.stats(), expected.stats());
+            assert_eq!(buf.0.take(), expected_explanation);
+        }
+        std::fs::remove_file(&path).expect("Input is removed");
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-max-errors-{}.csv",
+            std::process::id()
+        ));
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,x,2,10\n\
+            withdrawal,1,3,20\n\
+            deposit,1,4,oops\n\
+            deposit,1,5,10\n";
+        std::fs::write(&path, input).expect("Input is written");
+
+        let mut engine = PaymentsEngine::with_config(
+            path.to_string_lossy().into_owned(),
+            EngineConfig {
+                max_errors: Some(2),
+                ..Default::default()
+            },
+        );
+        let res = engine.run();
+        std::fs::remove_file(&path).expect("Input is removed");
+
+        // The run stops at the third failure, before the last deposit
+        assert!(matches!(res, Err(Error::TooManyErrors(3))));
+        assert_eq!(engine.stats().records(), 4);
+        assert_eq!(engine.available(1), Some(Decimal::new(10, 0)));
+    }
+
+    #[test]
+    fn test_run_str() {
+        let input = "type,client,tx,amount\n\
+            deposit,2,1,10\n\
+            deposit,1,2,5.5\n\
+            withdrawal,2,3,20\n\
+            dispute,1,2,\n\
+            deposit,x,4,1\n";
+        let (summaries, report) = run_str(input, EngineConfig::default());
+
+        let clients: Vec<_> = summaries.iter().map(|summary| summary.client).collect();
+        assert_eq!(clients, [1, 2]);
+        assert_eq!(summaries[0].available, Decimal::ZERO);
+        assert_eq!(summaries[0].held, Decimal::new(55, 1));
+        assert_eq!(summaries[1].available, Decimal::new(10, 0));
+        assert_eq!(report.stats.processed, 3);
+        assert_eq!(report.stats.rejected, 1);
+        assert_eq!(report.stats.malformed, 1);
+        assert_eq!(
+            report
+                .errors
+                .group("NotEnoughFunds")
+                .expect("Withdrawal is rejected")
+                .example_tx_ids,
+            [3]
+        );
+        assert_eq!(report.aborted, None);
+
+        // An aborted run is reported along with the accounts processed before it
+        let config = EngineConfig {
+            max_errors: Some(1),
+            ..Default::default()
+        };
+        let (summaries, report) = run_str(input, config);
+        assert_eq!(report.aborted, Some(Error::TooManyErrors(2).to_string()));
+        assert_eq!(report.stats.records(), 5);
+        assert_eq!(summaries.len(), 2);
+    }
+
+    #[test]
+    fn test_check_invariants_each_step() {
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                check_invariants_each_step: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader("type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,10\n".as_bytes());
+        engine.check_errors().expect("Invariants hold");
+
+        // Corrupt the account of client 1, which is only noticed once it is touched again
+        engine.accounts.get_mut(&1).expect("Account exists").held = -Decimal::ONE;
+        engine.process_reader(
+            "type,client,tx,amount\n\
+            deposit,2,3,10\n\
+            deposit,1,4,10\n\
+            deposit,2,5,10\n"
+                .as_bytes(),
+        );
+        assert!(matches!(
+            engine.check_errors(),
+            Err(Error::StepInvariantViolation(
+                4,
+                1,
+                "held funds are negative"
+            ))
+        ));
+        // The run stops at the offending transaction
+        assert_eq!(engine.stats().processed, 4);
+        assert_eq!(engine.available(2), Some(Decimal::new(20, 0)));
+
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Deposit, 1, 6, Some(Decimal::ONE))),
+            Err(Error::StepInvariantViolation(6, 1, _))
+        ));
+    }
+
+    #[test]
+    fn test_export_ledger() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/simple_deposit.csv".to_string(),
+            EngineConfig {
+                track_arrival: true,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 2, 7, Some(Decimal::new(25, 1))))
+            .expect("Deposit is applied");
+
+        let mut ledger = Vec::new();
+        engine.export_ledger(&mut ledger);
+        let mut imported = PaymentsEngine::new(String::new());
+        imported.process_reader(ledger.as_slice());
+
+        assert_eq!(imported.stats().processed, 2);
+        assert_eq!(imported.summaries(), engine.summaries());
+        let mut exported = Vec::new();
+        imported.export_ledger(&mut exported);
+        assert_eq!(exported, ledger);
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut expected = PaymentsEngine::new("examples/disputes.csv".to_string());
+        expected.run().expect("Input is processed");
+
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string())
+            .with_progress_writer(std::io::sink());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.summaries(), expected.summaries());
+        assert_eq!(engine.stats(), expected.stats());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-checkpoint-{}.json",
+            std::process::id()
+        ));
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,20\n\
+            withdrawal,1,3,5\n\
+            dispute,2,2,\n\
+            deposit,3,4,30\n\
+            resolve,2,2,\n\
+            withdrawal,3,5,1\n\
+            deposit,1,6,1\n\
+            withdrawal,2,7,100\n\
+            deposit,4,8,4\n";
+        let config = EngineConfig {
+            checkpoint_every: NonZeroUsize::new(5),
+            checkpoint_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut engine = PaymentsEngine::with_config(String::new(), config.clone());
+        engine.process_reader(input.as_bytes());
+
+        // Checkpoints are taken before reading the next record, so the only one covers the
+        // first 5 records
+        let file = std::fs::File::open(&path).expect("Checkpoint is written");
+        let snapshot = EngineSnapshot::read_json(file).expect("Checkpoint is valid");
+        std::fs::remove_file(&path).expect("Checkpoint is removed");
+        let mut restored = PaymentsEngine::with_config(String::new(), config);
+        restored.restore(snapshot);
+        let mut reference = PaymentsEngine::new(String::new());
+        let first_five: Vec<_> = input.lines().take(6).collect();
+        reference.process_reader(first_five.join("\n").as_bytes());
+        assert_eq!(restored.summaries(), reference.summaries());
+        assert_eq!(restored.stats(), reference.stats());
+
+        // Resuming the same input skips the records covered by the checkpoint
+        restored.process_reader(input.as_bytes());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(restored.summaries(), engine.summaries());
+        assert_eq!(restored.stats(), engine.stats());
+        restored.check_invariants().expect("Invariants hold");
+    }
+
+    #[test]
+    fn test_checkpoint_consecutive_runs() {
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "payments-engine-test-consecutive-{}-{}",
+                std::process::id(),
+                name
+            ))
+        };
+        let (first, second, checkpoint) = (path("in1.csv"), path("in2.csv"), path("ck.json"));
+        std::fs::write(
+            &first,
+            "type,client,tx,amount\n\
+                deposit,1,1,10\n\
+                deposit,1,2,10\n\
+                deposit,1,3,10\n\
+                deposit,1,4,10\n",
+        )
+        .expect("Input is written");
+        std::fs::write(
+            &second,
+            "type,client,tx,amount\n\
+                deposit,2,5,5\n\
+                deposit,2,6,5\n\
+                deposit,2,7,5\n",
+        )
+        .expect("Input is written");
+        let config = EngineConfig {
+            checkpoint_every: NonZeroUsize::new(2),
+            checkpoint_path: Some(checkpoint.clone()),
+            ..Default::default()
+        };
+        let engine = |input: &Path| {
+            PaymentsEngine::with_config(input.to_string_lossy().into_owned(), config.clone())
+        };
+
+        // A complete run removes its checkpoint, so the next run starts afresh
+        let mut run = engine(&first);
+        run.run().expect("Input is processed");
+        assert!(!checkpoint.exists());
+        let mut run = engine(&second);
+        run.run().expect("Input is processed");
+        assert_eq!(run.clients(), [2]);
+        assert_eq!(run.available(2), Some(Decimal::new(15, 0)));
+
+        // The checkpoint of an unfinished run only resumes the same input
+        let mut unfinished = engine(&first);
+        unfinished.process_file(&first).expect("Input is processed");
+        let read = || {
+            let file = std::fs::File::open(&checkpoint).expect("Checkpoint is written");
+            EngineSnapshot::read_json(file).expect("Checkpoint is valid")
+        };
+        let mut other = engine(&second);
+        assert!(matches!(
+            other.resume_from(read()),
+            Err(Error::CheckpointMismatch(_))
+        ));
+        assert!(other.clients().is_empty());
+        let mut resumed = engine(&first);
+        resumed.resume_from(read()).expect("Checkpoint matches");
+        resumed.run().expect("Input is processed");
+        assert_eq!(resumed.summaries(), unfinished.summaries());
+        assert!(!checkpoint.exists());
+
+        std::fs::remove_file(&first).expect("Input is removed");
+        std::fs::remove_file(&second).expect("Input is removed");
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(
+            "type,client,tx,amount\n\
+                deposit,1,1,10\n\
+                deposit,2,2,20\n\
+                deposit,3,3,30\n"
+                .as_bytes(),
+        );
+        let baseline = engine.snapshot();
+        assert!(engine.changed_since(&baseline).is_empty());
+
+        // The deposit to client 1 is cancelled out by the withdrawal
+        engine.process_reader(
+            "type,client,tx,amount\n\
+                withdrawal,2,4,5\n\
+                deposit,1,5,1\n\
+                withdrawal,1,6,1\n"
+                .as_bytes(),
+        );
+        let changed = engine.changed_since(&baseline);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].client, 2);
+        assert_eq!(changed[0].available, Decimal::new(15, 0));
+    }
+
+    #[test]
+    fn test_balance_accessors() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        assert_eq!(engine.available(1), Some(Decimal::new(100, 0)));
+        assert_eq!(engine.available(2), Some(Decimal::ZERO));
+        assert_eq!(engine.held(1), Some(Decimal::ZERO));
+        assert_eq!(engine.held(2), Some(Decimal::new(100, 0)));
+        assert_eq!(engine.total(2), Some(Decimal::new(100, 0)));
+        assert_eq!(engine.is_locked(2), Some(false));
+
+        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.total(1), Some(Decimal::ZERO));
+        assert_eq!(engine.is_locked(1), Some(true));
+
+        assert_eq!(engine.available(9), None);
+        assert_eq!(engine.held(9), None);
+        assert_eq!(engine.total(9), None);
+        assert_eq!(engine.is_locked(9), None);
+    }
+
+    #[test]
+    fn test_time_window() {
+        let input = "type,client,tx,amount,dest,timestamp\n\
+            deposit,1,1,10,,100\n\
+            deposit,1,2,20,,200\n\
+            withdrawal,1,3,5,,250\n\
+            deposit,1,4,40,,300\n\
+            dispute,1,1,,,\n\
+            dispute,1,2,,,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                from_timestamp: Some(200),
+                to_timestamp: Some(300),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // Only the deposit and withdrawal at 200 and 250 apply, the untimestamped disputes are
+        // processed and the one of the skipped deposit is rejected
+        assert_eq!(engine.stats().outside_window, 2);
+        assert_eq!(engine.available(1), Some(Decimal::new(-5, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::new(20, 0)));
+        assert_eq!(
+            engine
+                .error_report()
+                .group("TxDoesNotExist")
+                .map(|g| g.count),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_channels() {
+        let input = "type,client,tx,amount,channel\n\
+            deposit,1,1,10,web\n\
+            deposit,1,2,20,atm\n\
+            deposit,1,3,40,api\n\
+            withdrawal,1,4,5,atm\n\
+            deposit,1,5,80,\n\
+            dispute,1,3,,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                channels: Some(vec!["web".to_string(), "api".to_string()]),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The atm records are skipped, the records without a channel are processed
+        assert_eq!(engine.stats().other_channel, 2);
+        assert_eq!(engine.stats().processed, 4);
+        assert_eq!(engine.available(1), Some(Decimal::new(90, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::new(40, 0)));
+        let channels: Vec<_> = [1, 3, 5]
+            .iter()
+            .map(|id| engine.txs.get(id).expect("Deposit is retained").channel())
+            .collect();
+        assert_eq!(channels, [Some("web"), Some("api"), None]);
+
+        // The channels are kept by the snapshots, but not written out with the transactions
+        let mut json = Vec::new();
+        engine
+            .snapshot()
+            .write_json(&mut json)
+            .expect("Snapshot is written");
+        let mut restored = PaymentsEngine::new(String::new());
+        restored.restore(EngineSnapshot::read_json(json.as_slice()).expect("Snapshot is read"));
+        let tx = restored.txs.get(&1).expect("Deposit is restored");
+        assert_eq!(tx.channel(), Some("web"));
+        let mut buf = Vec::new();
+        restored.emit_transactions(&mut buf);
+        let emitted = String::from_utf8(buf).unwrap();
+        assert!(emitted.starts_with("type,client,tx,amount,dest,timestamp\n"));
+        assert!(!emitted.contains("web"));
+    }
+
+    #[test]
+    fn test_dust_balance() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10\n\
+                     withdrawal,1,2,9.9999\n\
+                     withdrawal,1,3,9.99\n\
+                     deposit,2,4,10\n\
+                     withdrawal,2,5,9.9999\n";
+        let config = EngineConfig {
+            dust_threshold: Some(Decimal::new(1, 2)),
+            ..Default::default()
+        };
+        let mut engine = PaymentsEngine::with_config(String::new(), config.clone());
+        engine.process_reader(input.as_bytes());
+
+        // Leaving 0.0001 is rejected, while leaving the threshold itself is not
+        assert_eq!(
+            engine.error_report().group("DustBalance").map(|g| g.count),
+            Some(2)
+        );
+        assert_eq!(engine.available(1), Some(Decimal::new(1, 2)));
+        assert_eq!(engine.available(2), Some(Decimal::new(10, 0)));
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                dust_action: DustAction::Sweep,
+                ..config.clone()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.available(2), Some(Decimal::ZERO));
+        assert_eq!(engine.txs[&5].amount, Some(Decimal::new(10, 0)));
+
+        // A remainder that overflows is left to the funds check
+        let input = format!(
+            "type,client,tx,amount\n\
+            deposit,1,1,{max}\n\
+            withdrawal,1,2,{max}\n\
+            dispute,1,1,\n\
+            withdrawal,1,3,{max}\n",
+            max = Decimal::MAX
+        );
+        let mut engine = PaymentsEngine::with_config(String::new(), config);
+        engine.process_reader(input.as_bytes());
+        assert_eq!(
+            engine
+                .error_report()
+                .group("NotEnoughFunds")
+                .map(|group| group.count),
+            Some(1)
+        );
+    }
+
+    // Transaction store recording the operations made by the engine
+    #[derive(Default)]
+    struct RecordingStore {
+        txs: HashMap<u32, Tx>,
+        ops: RefCell<Vec<String>>,
+    }
+
+    impl Store<u32, Tx> for RecordingStore {
+        fn get(&self, key: &u32) -> Option<&Tx> {
+            self.ops.borrow_mut().push(format!("get {}", key));
+            self.txs.get(key)
+        }
+
+        fn get_mut(&mut self, key: &u32) -> Option<&mut Tx> {
+            self.ops.borrow_mut().push(format!("get_mut {}", key));
+            self.txs.get_mut(key)
+        }
+
+        fn insert(&mut self, key: u32, value: Tx) -> Option<Tx> {
+            self.ops.borrow_mut().push(format!("insert {}", key));
+            self.txs.insert(key, value)
+        }
+
+        fn remove(&mut self, key: &u32) -> Option<Tx> {
+            self.ops.borrow_mut().push(format!("remove {}", key));
+            self.txs.remove(key)
+        }
+
+        fn clear(&mut self) {
+            self.ops.borrow_mut().push("clear".to_string());
+            self.txs.clear()
+        }
+
+        fn iter(&self) -> impl Iterator<Item = (&u32, &Tx)> {
+            self.ops.borrow_mut().push("iter".to_string());
+            self.txs.iter()
+        }
+
+        fn len(&self) -> usize {
+            self.txs.len()
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let mut engine = PaymentsEngine::with_stores(
+            String::new(),
+            EngineConfig::default(),
+            HashMap::new(),
+            RecordingStore::default(),
+            RecordingStore::default(),
+        );
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0))))
+            .expect("Deposit is applied");
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 1, None))
+            .expect("Dispute is applied");
+        engine
+            .process_tx(Tx::new(TxType::ChargeBack, 1, 1, None))
+            .expect("Chargeback is applied");
+
+        // The charged back deposit and its dispute are dropped
+        assert_eq!(
+            *engine.txs.ops.borrow(),
+            ["insert 1", "get 1", "get 1", "remove 1"]
+        );
+        assert_eq!(
+            *engine.disputes.ops.borrow(),
+            ["get 1", "insert 1", "get 1", "remove 1"]
+        );
+        assert_eq!(engine.available(1), Some(Decimal::ZERO));
+        assert_eq!(engine.is_locked(1), Some(true));
+    }
+
+    #[test]
+    fn test_process_txs_detailed() {
+        let mut engine = PaymentsEngine::new(String::new());
+        let results = engine.process_txs_detailed(vec![
+            Tx::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0))),
+            Tx::new(TxType::Withdrawal, 1, 2, Some(Decimal::new(20, 0))),
+            Tx::new(TxType::Dispute, 1, 1, None),
+            Tx::new(TxType::Dispute, 1, 1, None),
+            Tx::new(TxType::Resolve, 1, 3, None),
+            Tx::new(TxType::Resolve, 1, 1, None),
+        ]);
+
+        assert_eq!(results.len(), 6);
+        assert!(matches!(results[0], Ok(())));
+        assert!(matches!(results[1], Err(Error::NotEnoughFunds(2))));
+        assert!(matches!(results[2], Ok(())));
+        assert!(matches!(results[3], Err(Error::TxAlreadyUnderDispute(1))));
+        assert!(matches!(results[4], Err(Error::TxDoesNotExist(3))));
+        assert!(matches!(results[5], Ok(())));
+        assert_eq!(engine.available(1), Some(Decimal::new(10, 0)));
+    }
+
+    #[test]
+    fn test_clients() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.clients(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_fund_totals() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 2, None))
+            .expect("Deposit can be disputed");
+
+        assert_eq!(engine.total_available().unwrap(), Decimal::new(-507, 1));
+        assert_eq!(engine.total_held().unwrap(), Decimal::new(1002, 1));
+        assert_eq!(engine.grand_total().unwrap(), Decimal::new(495, 1));
+
+        for client in [3, 4] {
+            engine
+                .process_tx(Tx::new(
+                    TxType::Deposit,
+                    client,
+                    5 + client as u32,
+                    Some(Decimal::MAX),
+                ))
+                .expect("Deposit is valid");
+        }
+        assert!(matches!(engine.total_available(), Err(Error::SumOverflow)));
+        assert!(matches!(engine.grand_total(), Err(Error::SumOverflow)));
+    }
+
+    #[test]
+    fn test_all_or_nothing() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-all-or-nothing-{}.csv",
+            std::process::id()
+        ));
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,ten\n\
+            deposit,3,3,30\n";
+        std::fs::write(&path, input).expect("Input is written");
+        let config = EngineConfig {
+            all_or_nothing: true,
+            ..Default::default()
+        };
+        let mut engine = PaymentsEngine::with_config(path.display().to_string(), config.clone());
+        let res = engine.run();
+        assert!(matches!(res, Err(Error::AmountParse(2))));
+        assert!(engine.summaries().is_empty());
+        assert_eq!(engine.stats(), &Stats::default());
+
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,10\ndeposit\n")
+            .expect("Input is written");
+        let mut engine = PaymentsEngine::with_config(path.display().to_string(), config);
+        let res = engine.run();
+        std::fs::remove_file(&path).expect("Input is removed");
+        assert!(matches!(res, Err(Error::MalformedRecord(3, _))));
+        assert!(engine.summaries().is_empty());
+    }
+
+    #[test]
+    fn test_run_directory() {
+        let dir = std::env::temp_dir().join(format!(
+            "payments-engine-test-directory-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).expect("Directory is created");
+        let first = "type,client,tx,amount\ndeposit,1,1,100\ndeposit,2,2,50\n";
+        let second = "type,client,tx,amount\nwithdrawal,1,3,30\ndispute,2,2,\n";
+        std::fs::write(dir.join("2024-01-01T01.csv"), first).expect("File is written");
+        std::fs::write(dir.join("2024-01-01T02.csv"), second).expect("File is written");
+        std::fs::write(dir.join("notes.txt"), "withdrawal,1,4,70\n").expect("File is written");
+
+        let mut engine = PaymentsEngine::new(dir.display().to_string());
+        let res = engine.run();
+        std::fs::remove_dir_all(&dir).expect("Directory is removed");
+        res.expect("Input is processed");
+
+        let mut concatenated = PaymentsEngine::new(String::new());
+        let (_, second_records) = second.split_once('\n').expect("File has a header");
+        concatenated.process_reader(format!("{}{}", first, second_records).as_bytes());
+        assert_eq!(engine.summaries(), concatenated.summaries());
+        assert_eq!(engine.stats(), concatenated.stats());
+        assert_eq!(engine.stats().processed, 4);
+    }
+
+    #[test]
+    fn test_arrival_index() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,5,100\n\
+            deposit,1,2,50\n\
+            withdrawal,1,9,500\n\
+            dispute,1,5,\n\
+            withdrawal,1,1,20\n\
+            deposit,2,3,10\n";
+        let indices = |engine: &PaymentsEngine| {
+            engine
+                .history(1)
+                .iter()
+                .map(|tx| (tx.id(), tx.arrival_index().expect("Arrival is tracked")))
+                .collect::<Vec<_>>()
+        };
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                track_arrival: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        // The rejected withdrawal and the dispute don't take part in the history
+        assert_eq!(indices(&engine), vec![(5, 0), (2, 1), (1, 3)]);
+        assert_eq!(engine.history(2)[0].arrival_index(), Some(4));
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                track_arrival: true,
+                count_rejected_arrivals: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(indices(&engine), vec![(5, 0), (2, 1), (1, 4)]);
+    }
+
+    #[test]
+    fn test_statement() {
+        let input = "type,client,tx,amount,dest,timestamp,client_name\n\
+            deposit,1,5,100,,,Alice\n\
+            deposit,1,2,50.5,,,\n\
+            withdrawal,1,9,20,,,\n\
+            transfer,1,3,10,2,,\n\
+            dispute,1,2,,,,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                track_arrival: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        let mut buf = Vec::new();
+        engine.statement(1, &mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "Statement for client 1 (Alice)\n  \
+                tx 5: deposit of 100\n  \
+                tx 2: deposit of 50.5\n  \
+                tx 9: withdrawal of 20\n  \
+                tx 3: transfer of 10 to client 2\n\
+                Balance: available 70, held 50.5, total 120.5\n"
+        );
+
+        let mut buf = Vec::new();
+        engine.statement(3, &mut buf);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_simulate() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+        let before = engine.summaries();
+
+        let withdrawal = Tx::new(TxType::Withdrawal, 1, 10, Some(Decimal::new(40, 0)));
+        let simulated = engine.simulate(&withdrawal).expect("Withdrawal is valid");
+        assert_eq!(simulated.available, Decimal::new(60, 0));
+        assert_eq!(simulated.total, Decimal::new(60, 0));
+        assert_eq!(engine.summaries(), before);
+        assert_eq!(engine.available(1), Some(Decimal::new(100, 0)));
+
+        // The errors match the ones of process_tx
+        let chargeback = Tx::new(TxType::ChargeBack, 2, 2, None);
+        assert!(
+            engine
+                .simulate(&chargeback)
+                .expect("Chargeback is valid")
+                .locked
+        );
+        let overdraft = Tx::new(TxType::Withdrawal, 1, 11, Some(Decimal::new(101, 0)));
+        assert!(matches!(
+            engine.simulate(&overdraft),
+            Err(Error::NotEnoughFunds(11))
+        ));
+        assert!(matches!(
+            engine.simulate(&Tx::new(TxType::Resolve, 1, 1, None)),
+            Err(Error::TxNotUnderDispute(1))
+        ));
+        assert_eq!(engine.summaries(), before);
+        engine.process_tx(withdrawal).expect("Withdrawal is valid");
+        assert_eq!(engine.available(1), Some(Decimal::new(60, 0)));
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut engine = PaymentsEngine::new(String::new());
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0))))
+            .expect("Deposit succeeds");
+
+        engine.pause();
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Deposit, 1, 2, Some(Decimal::ONE))),
+            Err(Error::EnginePaused(2))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::transfer(1, 2, 3, Decimal::ONE)),
+            Err(Error::EnginePaused(3))
+        ));
+        assert!(engine.account(2).is_none());
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 1, None))
+            .expect("Disputes are applied while paused");
+        assert_eq!(engine.held(1), Some(Decimal::new(10, 0)));
+
+        engine.resume();
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 2, Some(Decimal::ONE)))
+            .expect("Deposit succeeds after resume");
+        assert_eq!(engine.available(1), Some(Decimal::ONE));
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                pause_disputes: true,
+                ..Default::default()
+            },
+        );
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 1, Some(Decimal::new(10, 0))))
+            .expect("Deposit succeeds");
+        engine.pause();
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, None)),
+            Err(Error::EnginePaused(1))
+        ));
+    }
+
+    #[test]
+    fn test_row_with_too_few_columns() {
+        let input = "type,client,tx,amount\ndeposit\ndeposit,1\ndeposit,1,1,10\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().malformed, 2);
+        assert_eq!(engine.stats().processed, 1);
+        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(10, 0)
+        );
+    }
+
+    #[test]
+    fn test_dispute_rows_with_and_without_amount_column() {
+        // The amount column of dispute rows may be empty or left out
+        for header in [
+            "type,client,tx,amount",
+            "type,client,tx,amount,dest,timestamp",
+        ] {
+            let outputs = ["dispute,1,1,", "dispute,1,1"].map(|dispute| {
+                let input = format!("{}\ndeposit,1,1,10\n{}\n", header, dispute);
+                let mut engine = PaymentsEngine::new(String::new());
+                engine.process_reader(input.as_bytes());
+                assert_eq!(engine.stats().processed, 2);
+                assert_eq!(engine.held(1), Some(Decimal::new(10, 0)));
+
+                let mut buf = Vec::new();
+                engine.print_accounts(&mut buf);
+                buf
+            });
+            assert_eq!(outputs[0], outputs[1]);
+        }
+    }
+
+    #[test]
+    fn test_row_with_too_many_columns() {
+        let input = "type,client,tx,amount\ndeposit,1,1,10,extra,columns\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().malformed, 0);
+        assert_eq!(engine.stats().processed, 1);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(10, 0)
+        );
+    }
+
+    #[test]
+    fn test_transaction_group_rollback() {
+        let mut engine = PaymentsEngine::new("examples/simple_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        let res = engine.transaction_group(|engine| {
+            engine.process_tx(Tx::new(TxType::Withdrawal, 1, 2, Some(Decimal::new(50, 0))))?;
+            // Client 2 has no funds so the second leg fails
+            engine.process_tx(Tx::new(TxType::Withdrawal, 2, 3, Some(Decimal::new(50, 0))))
+        });
+
+        assert!(matches!(res, Err(Error::NotEnoughFunds(3))));
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(1001001, 4)
+        );
+        assert!(!engine.txs.contains_key(&2));
+        assert!(!engine.accounts.contains_key(&2));
+    }
+
+    #[test]
+    fn test_transaction_group_audit() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-group-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).expect("Audit log is created");
+        let mut engine = PaymentsEngine::new(String::new()).with_audit_writer(file);
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 1, Some(Decimal::new(100, 0))))
+            .expect("Deposit is applied");
+
+        // The records of the rolled back group are dropped, those of the committed one written
+        let res = engine.transaction_group(|engine| {
+            engine.process_tx(Tx::new(TxType::Withdrawal, 1, 2, Some(Decimal::new(50, 0))))?;
+            engine.process_tx(Tx::new(TxType::Withdrawal, 2, 3, Some(Decimal::new(50, 0))))
+        });
+        assert!(matches!(res, Err(Error::NotEnoughFunds(3))));
+        engine
+            .transaction_group(|engine| {
+                engine.process_tx(Tx::new(TxType::Withdrawal, 1, 4, Some(Decimal::new(30, 0))))?;
+                engine.process_tx(Tx::new(TxType::Deposit, 2, 5, Some(Decimal::new(10, 0))))
+            })
+            .expect("Group is applied");
+
+        let audit = std::fs::read_to_string(&path).expect("Audit log is written");
+        std::fs::remove_file(&path).expect("Audit log is removed");
+        let txs: Vec<u32> = audit
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<AuditRecord>(line)
+                    .expect("Audit record is valid")
+                    .tx
+            })
+            .collect();
+        assert_eq!(txs, [1, 4, 5]);
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            deposit,2,2,50\n\
+            withdrawal,1,3,10\n\
+            withdrawal,2,4,10\n\
+            dispute,2,2,\n\
+            deposit,3,5,20\n\
+            dispute,3,5,\n\
+            chargeback,3,5,\n\
+            withdrawal,1,6,10\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        let expected = MemoryStats {
+            txs: 5,
+            disputes: 1,
+            accounts: 3,
+            charged_back: 1,
+        };
+        assert_eq!(engine.memory_stats(), expected);
+
+        // Compacting every 4 records drops the two withdrawals read by then, but not the last
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                compact_every: NonZeroUsize::new(4),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.memory_stats().txs, 3);
+        assert_eq!(engine.compact(), 1);
+        assert_eq!(engine.memory_stats().txs, 2);
+        assert_eq!(engine.available(1), Some(Decimal::new(80, 0)));
+    }
+
+    #[test]
+    fn test_open_dispute_details() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        assert_eq!(
+            engine.open_dispute_details(),
+            vec![DisputeInfo {
+                tx: 2,
+                client: 2,
+                held: Decimal::new(100, 0),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_buffer_bytes() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        engine.run().expect("Input is processed");
+        let mut buffered = PaymentsEngine::with_config(
+            "examples/deposits_and_withdrawals.csv".to_string(),
+            EngineConfig {
+                read_buffer_bytes: Some(8),
+                ..Default::default()
+            },
+        );
+        buffered.run().expect("Input is processed");
+
+        assert_eq!(buffered.accounts.len(), engine.accounts.len());
+        for (client, account) in &engine.accounts {
+            let other = buffered.accounts.get(client).expect("Account exists");
+            assert_eq!(other.available, account.available);
+            assert_eq!(other.held, account.held);
+            assert_eq!(other.locked, account.locked);
+        }
+    }
+
+    #[test]
+    fn test_partial_over_dispute() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            dispute,1,1,60\n\
+            dispute,1,1,50\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // The second partial dispute would hold more than was deposited
+        assert_eq!(engine.stats().rejected, 1);
+        let account = engine.accounts.get(&1).expect("Account exists");
+        assert_eq!(account.available, Decimal::new(40, 0));
+        assert_eq!(account.held, Decimal::new(60, 0));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, Some(Decimal::new(41, 0)))),
+            Err(Error::OverDispute(1))
+        ));
+    }
+
+    #[test]
+    fn test_buffer_orphan_disputes() {
+        let input = "type,client,tx,amount\n\
+            dispute,1,5,\n\
+            dispute,2,9,\n\
+            deposit,1,5,100\n\
+            deposit,2,6,10\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                buffer_orphan_disputes: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The dispute of tx 5 is applied once the deposit arrives
+        assert_eq!(engine.available(1), Some(Decimal::ZERO));
+        assert_eq!(engine.held(1), Some(Decimal::new(100, 0)));
+        let orphans: Vec<_> = engine.orphan_disputes().iter().map(|tx| tx.id()).collect();
+        assert_eq!(orphans, vec![9]);
+        assert_eq!(engine.stats().processed, 3);
+        assert_eq!(engine.stats().rejected, 0);
+        assert_eq!(engine.stats().orphan_disputes, 1);
+        assert_eq!(engine.stats().records(), 4);
+        engine.check_invariants().expect("Invariants hold");
+
+        // Without the buffer, the early dispute is rejected
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.held(1), Some(Decimal::ZERO));
+        assert_eq!(engine.stats().rejected, 2);
+    }
+
+    #[test]
+    fn test_strict_dispute_rows() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            dispute,1,1,60\n\
+            dispute,1,1,\n\
+            resolve,1,1,100\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                strict_dispute_rows: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // Only the dispute without an amount is applied
+        assert_eq!(engine.stats().rejected, 2);
+        assert_eq!(
+            engine
+                .error_report()
+                .group("UnexpectedAmount")
+                .map(|g| g.count),
+            Some(2)
+        );
+        assert_eq!(engine.available(1), Some(Decimal::ZERO));
+        assert_eq!(engine.held(1), Some(Decimal::new(100, 0)));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, Some(Decimal::new(1, 0)))),
+            Err(Error::UnexpectedAmount(1))
+        ));
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/deposits_and_withdrawals.csv".to_string(),
+            EngineConfig {
+                sample_rate: NonZeroUsize::new(2).unwrap(),
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        // Only the first, third and fifth records are applied
+        assert_eq!(engine.stats().processed, 3);
+        assert_eq!(engine.stats().sampled_out, 2);
+        assert_eq!(
+            engine.accounts.get(&1).expect("Account exists").available,
+            Decimal::new(1, 4)
+        );
+        assert_eq!(
+            engine.accounts.get(&2).expect("Account exists").available,
+            Decimal::new(495, 1)
+        );
+    }
+
+    #[test]
+    fn test_summaries_match_printed_accounts() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        let printed = ReaderBuilder::new()
+            .from_reader(buf.as_slice())
+            .deserialize()
+            .collect::<std::result::Result<Vec<AccountSummary>, _>>()
+            .expect("Printed accounts are valid CSV");
+
+        let summaries = engine.summaries();
+        assert_eq!(summaries, printed);
+        assert_eq!(
+            summaries.iter().map(|s| s.client).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_dispute_references_non_money_tx() {
+        let mut engine = PaymentsEngine::new("examples/simple_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // Tx 2 was only ever used by a (rejected) dispute row
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 2, None)),
+            Err(Error::TxDoesNotExist(2))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Resolve, 1, 2, None)),
+            Err(Error::TxDoesNotExist(2))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::ChargeBack, 1, 2, None)),
+            Err(Error::TxDoesNotExist(2))
+        ));
+        assert!(engine.disputes.is_empty());
+        assert!(!engine.accounts.get(&1).expect("Account exists").locked);
+    }
+
+    #[test]
+    fn test_activity_sequence() {
+        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string())
+            .with_clock(SequenceClock::starting_at(10));
+        engine.run().expect("Input is processed");
+
+        // Client 1 has records 1, 2 and 4, client 2 has records 3 and 5
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.first_seen_seq(), 10);
+        assert_eq!(account.last_activity_seq(), 13);
+        let account = engine.account(2).expect("Account exists");
+        assert_eq!(account.first_seen_seq(), 12);
+        assert_eq!(account.last_activity_seq(), 14);
+    }
+
+    #[test]
+    fn test_chargeback_never_drives_balances_negative() {
+        let cases = [
+            // Full chargeback
+            "deposit,1,1,100\ndispute,1,1,\n",
+            // Partial chargeback
+            "deposit,1,1,100\ndispute,1,1,30\n",
+            // Chargeback of funds that were already withdrawn
+            "deposit,1,1,100\nwithdrawal,1,2,80\ndispute,1,1,\n",
+            // Chargeback of one of several open disputes
+            "deposit,1,1,100\ndeposit,1,2,50\ndispute,1,1,\ndispute,1,2,20\n",
+        ];
+        for case in cases {
+            let input = format!("type,client,tx,amount\n{}", case);
+            let mut engine = PaymentsEngine::new(String::new());
+            engine.process_reader(input.as_bytes());
+            let before = engine.account(1).expect("Account exists").clone();
+
+            engine
+                .process_tx(Tx::new(TxType::ChargeBack, 1, 1, None))
+                .expect("Chargeback succeeds");
+
+            let after = engine.account(1).expect("Account exists");
+            assert_eq!(after.available, before.available, "{}", case);
+            assert!(after.held >= Decimal::ZERO, "{}", case);
+            assert!(after.held < before.held, "{}", case);
+            assert!(after.locked, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_transfer() {
+        let input = "type,client,tx,amount,dest\n\
+            deposit,1,1,100,\n\
+            transfer,1,2,40,2\n\
+            transfer,1,3,70,2\n\
+            transfer,1,4,10,1\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // The second transfer exceeds the funds left and the third is to the same client
+        assert_eq!(engine.stats().rejected, 2);
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(60, 0)
+        );
+        assert_eq!(
+            engine.account(2).expect("Account exists").available,
+            Decimal::new(40, 0)
+        );
+        assert!(engine.txs.contains_key(&2));
+
+        // Transfers to a locked account are rejected without debiting the source
+        engine.accounts.get_mut(&2).expect("Account exists").locked = true;
+        assert!(matches!(
+            engine.process_tx(Tx::transfer(1, 2, 5, Decimal::new(10, 0))),
+            Err(Error::AccountLocked(2))
+        ));
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(60, 0)
+        );
+    }
+
+    #[test]
+    fn test_id_reuse_after_chargeback() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,2,1,50\n\
+            withdrawal,2,2,500\n\
+            deposit,2,2,20\n";
+
+        // By default, the charged back id is free for a fresh deposit, which can be disputed
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(engine.available(2), Some(Decimal::new(70, 0)));
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 2, 1, None))
+            .expect("Fresh deposit can be disputed");
+
+        // The rejected withdrawal doesn't use up its id
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                forbid_id_reuse: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().rejected, 2);
+        assert_eq!(
+            engine.error_report().group("IdReused").map(|g| g.count),
+            Some(1)
+        );
+        assert_eq!(engine.available(2), Some(Decimal::new(20, 0)));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Withdrawal, 2, 2, Some(Decimal::ONE))),
+            Err(Error::IdReused(2))
+        ));
+    }
+
+    #[test]
+    fn test_double_chargeback() {
+        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+        // Unlock the account so the second chargeback isn't rejected for that reason
+        engine.accounts.get_mut(&1).expect("Account exists").locked = false;
+
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::ChargeBack, 1, 1, None)),
+            Err(Error::AlreadyChargedBack(1))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, None)),
+            Err(Error::AlreadyChargedBack(1))
+        ));
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_input_format_from_path() {
+        assert_eq!(InputFormat::from_path("txs.csv").unwrap(), InputFormat::Csv);
+        assert_eq!(
+            InputFormat::from_path("txs.jsonl").unwrap(),
+            InputFormat::Jsonl
+        );
+        assert_eq!(InputFormat::from_path("txs.txt").unwrap(), InputFormat::Csv);
+        assert!(matches!(
+            InputFormat::from_path("txs.csv.gz"),
+            Err(Error::UnsupportedInput(_))
+        ));
+        assert!(matches!(
+            InputFormat::from_path("txs.jsonl.gz"),
+            Err(Error::UnsupportedInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_jsonl_input() {
+        let mut csv = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
+        csv.run().expect("Input is processed");
+        let mut jsonl = PaymentsEngine::new("examples/deposits_and_withdrawals.jsonl".to_string());
+        jsonl.run().expect("Input is processed");
+
+        assert_eq!(jsonl.stats(), csv.stats());
+        assert_eq!(jsonl.summaries(), csv.summaries());
+
+        // Unknown extensions are parsed as CSV
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-jsonl-input-{}.txt",
+            std::process::id()
+        ));
+        std::fs::copy("examples/deposits_and_withdrawals.csv", &path).expect("Input is copied");
+        let mut txt = PaymentsEngine::new(path.display().to_string());
+        txt.run().expect("Input is processed");
+        std::fs::remove_file(&path).expect("Input is removed");
+        assert_eq!(txt.stats(), csv.stats());
+        assert_eq!(txt.summaries(), csv.summaries());
+
+        // Compressed inputs are rejected before being opened
+        let mut gz = PaymentsEngine::new("examples/deposits_and_withdrawals.csv.gz".to_string());
+        assert!(matches!(gz.run(), Err(Error::UnsupportedInput(_))));
+        assert!(gz.summaries().is_empty());
+    }
+
+    #[test]
+    fn test_max_open_disputes_per_client() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,2,10\n\
+            deposit,1,3,10\n\
+            dispute,1,1,\n\
+            dispute,1,2,\n\
+            dispute,1,3,\n\
+            resolve,1,1,\n\
+            dispute,1,3,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                max_open_disputes_per_client: Some(2),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The first dispute of tx 3 is over the cap, the second one follows a resolve
+        assert_eq!(engine.stats().rejected, 1);
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.open_disputes, 2);
+        assert_eq!(account.available, Decimal::new(10, 0));
+        assert_eq!(account.held, Decimal::new(20, 0));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 1, None)),
+            Err(Error::TooManyOpenDisputes(1))
+        ));
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit() {
+        let day = SECONDS_PER_DAY;
+        let input = format!(
+            "type,client,tx,amount,dest,timestamp\n\
+            deposit,1,1,1000,,\n\
+            withdrawal,1,2,60,,{}\n\
+            withdrawal,1,3,50,,{}\n\
+            withdrawal,1,4,40,,{}\n\
+            withdrawal,1,5,100,,{}\n\
+            withdrawal,1,6,500,,\n",
+            day + 10,
+            2 * day - 1,
+            2 * day - 1,
+            2 * day
+        );
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                daily_withdrawal_limit: Some(Decimal::new(100, 0)),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The second withdrawal of the day crosses the limit, the next day starts afresh and
+        // untimestamped withdrawals are not limited
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(
+            engine
+                .error_report()
+                .group("DailyLimitExceeded")
+                .map(|g| g.count),
+            Some(1)
+        );
+        assert_eq!(engine.available(1), Some(Decimal::new(300, 0)));
+        assert!(matches!(
+            engine.process_tx(
+                Tx::new(TxType::Withdrawal, 1, 7, Some(Decimal::ONE)).with_timestamp(2 * day + 1)
+            ),
+            Err(Error::DailyLimitExceeded(1))
+        ));
+    }
+
+    #[test]
+    fn test_max_accounts() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,10\n\
+            deposit,3,3,10\n\
+            deposit,1,4,10\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                max_accounts: Some(2),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // Existing accounts keep accepting transactions once the cap is reached
+        assert_eq!(engine.stats().rejected, 1);
+        assert!(engine.account(3).is_none());
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(20, 0)
+        );
+        assert!(matches!(
+            engine.process_tx(Tx::transfer(1, 3, 5, Decimal::ONE)),
+            Err(Error::TooManyAccounts(3))
+        ));
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(20, 0)
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader("type,client,tx,amount\ndeposit,1,1,10\n".as_bytes());
+        let mut other = PaymentsEngine::new(String::new());
+        other.process_reader(
+            "type,client,tx,amount\ndeposit,2,2,5\ndispute,2,2,\nwithdrawal,2,3,1\n".as_bytes(),
+        );
 
-                match tx.ty {
-                    TxType::Deposit => {
-                        account.available += amount;
-                    }
-                    TxType::Withdrawal => {
-                        if account.available < amount {
-                            return Err(Error::NotEnoughFunds(tx.id));
-                        }
-                        account.available -= amount;
-                    }
-                    _ => unreachable!(),
-                }
-                self.txs.insert(tx.id, tx);
-            }
-            TxType::Dispute | TxType::Resolve | TxType::ChargeBack => {
-                if tx.amount.is_some() {
-                    return Err(Error::TxSpecifiesAmount(tx.ty));
-                }
-                let original_tx = self.txs.get(&tx.id).ok_or(Error::TxDoesNotExist(tx.id))?;
-                if tx.client != original_tx.client {
-                    return Err(Error::ClientIdMismatch(tx.ty, tx.id));
-                }
-                if !matches!(original_tx.ty, TxType::Deposit) {
-                    return Err(Error::OriginalTxNotDeposit(tx.ty, tx.id));
-                }
-                let amount = original_tx
-                    .amount
-                    .expect("Deposit transaction has an amount");
+        engine.merge(other).expect("Clients are disjoint");
+        let clients: Vec<_> = engine.summaries().iter().map(|s| s.client).collect();
+        assert_eq!(clients, vec![1, 2]);
+        assert_eq!(engine.stats().processed, 3);
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(engine.open_dispute_details().len(), 1);
+        engine.check_invariants().expect("Invariants hold");
 
-                match tx.ty {
-                    TxType::Dispute => {
-                        if self.disputes.contains_key(&tx.id) {
-                            return Err(Error::TxAlreadyUnderDispute(tx.id));
-                        }
-                        account.available -= amount;
-                        account.held += amount;
-                        self.disputes.insert(tx.id, tx);
-                    }
-                    TxType::Resolve => {
-                        // Cancellation of a dispute
-                        self.disputes
-                            .remove(&tx.id)
-                            .ok_or(Error::TxNotUnderDispute(tx.id))?;
-                        account.available += amount;
-                        account.held -= amount;
-                    }
-                    TxType::ChargeBack => {
-                        // Deposit reversal
-                        self.disputes
-                            .remove(&tx.id)
-                            .ok_or(Error::TxNotUnderDispute(tx.id))?;
-                        account.held -= amount;
-                        account.locked = true;
-                        self.txs.remove(&tx.id);
-                    }
-                    _ => unreachable!(),
-                }
-            }
+        // The merged dispute can be resolved
+        engine
+            .process_tx(Tx::new(TxType::Resolve, 2, 2, None))
+            .expect("Resolve succeeds");
+        assert_eq!(
+            engine.account(2).expect("Account exists").available,
+            Decimal::new(5, 0)
+        );
+
+        let mut overlapping = PaymentsEngine::new(String::new());
+        overlapping
+            .process_reader("type,client,tx,amount\ndeposit,3,4,1\ndeposit,2,5,1\n".as_bytes());
+        assert!(matches!(
+            engine.merge(overlapping),
+            Err(Error::MergeConflict(2))
+        ));
+        assert!(engine.account(3).is_none());
+
+        // Neither the retained transactions nor the disputes of another shard may collide
+        for (input, id) in [
+            ("type,client,tx,amount\ndeposit,3,4,1\ndeposit,4,2,1\n", 2),
+            (
+                "type,client,tx,amount\ndeposit,3,4,1\ndeposit,4,1,1\ndispute,4,1,\n",
+                1,
+            ),
+        ] {
+            let mut colliding = PaymentsEngine::new(String::new());
+            colliding.process_reader(input.as_bytes());
+            assert!(matches!(
+                engine.merge(colliding),
+                Err(Error::TxMergeConflict(conflict)) if conflict == id
+            ));
+            assert!(engine.account(3).is_none());
+            assert!(engine.account(4).is_none());
         }
-        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_dispute_with_mismatched_client() {
+        let input = "type,client,tx,amount\ndeposit,1,7,100\ndispute,2,7,\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().rejected, 1);
+        assert!(engine.disputes.is_empty());
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(100, 0)
+        );
+        // No phantom account is created for client 2
+        assert!(engine.account(2).is_none());
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,100,0,100,false\n"
+        );
+    }
 
     #[test]
-    fn test_deposits_and_withdrawals() {
-        let mut engine = PaymentsEngine::new("examples/deposits_and_withdrawals.csv".to_string());
-        engine.run();
-        assert_eq!(engine.accounts.len(), 2);
+    fn test_locked_tx_silent_skip() {
+        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(engine.stats().skipped_locked, 0);
+
+        let mut engine = PaymentsEngine::with_config(
+            "examples/reversed_deposit.csv".to_string(),
+            EngineConfig {
+                locked_tx_behavior: LockedTxBehavior::SilentSkip,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+        // The deposit following the chargeback is skipped instead of rejected
+        assert_eq!(engine.stats().rejected, 0);
+        assert_eq!(engine.stats().skipped_locked, 1);
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").available,
+            engine.account(1).expect("Account exists").available,
             Decimal::ZERO
         );
+    }
+
+    #[test]
+    fn test_locked_tx_replay() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            deposit,1,2,50\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n\
+            deposit,1,3,20\n\
+            dispute,1,2,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                locked_tx_behavior: LockedTxBehavior::Replay,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The deposit to the locked account is skipped, the dispute is still applied
+        assert_eq!(engine.stats().rejected, 0);
+        assert_eq!(engine.stats().skipped_locked, 1);
+        assert!(engine.error_report().is_empty());
+        let account = engine.account(1).expect("Account exists");
+        assert!(account.locked);
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::new(50, 0));
+        engine
+            .process_tx(Tx::new(TxType::Resolve, 1, 2, None))
+            .expect("Resolve succeeds");
         assert_eq!(
-            engine.accounts.get(&2).unwrap().available,
-            Decimal::new(4950, 2)
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(50, 0)
         );
     }
 
     #[test]
-    fn test_failed_withdrawal() {
-        let mut engine = PaymentsEngine::new("examples/failed_withdrawal.csv".to_string());
-        engine.run();
+    fn test_close_disputes_of_locked_account() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            deposit,1,2,50\n\
+            dispute,1,1,\n\
+            dispute,1,2,\n\
+            chargeback,1,1,\n\
+            resolve,1,2,\n\
+            deposit,1,3,20\n\
+            dispute,1,2,\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // The resolve releases the funds held by the second dispute, while the later deposit and
+        // dispute are rejected
+        let account = engine.account(1).expect("Account exists");
+        assert!(account.locked);
+        assert_eq!(account.available, Decimal::new(50, 0));
+        assert_eq!(account.held, Decimal::ZERO);
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").available,
-            Decimal::new(5000, 2)
+            engine
+                .error_report()
+                .group("AccountLocked")
+                .expect("Account is locked")
+                .example_tx_ids,
+            [3, 2]
         );
+        engine.check_invariants().expect("Invariants hold");
+
+        // A chargeback of a dispute left open also applies
+        let input = "type,client,tx,amount\n\
+            deposit,2,4,100\n\
+            deposit,2,5,50\n\
+            dispute,2,4,\n\
+            dispute,2,5,\n\
+            chargeback,2,4,\n\
+            chargeback,2,5,\n";
+        engine.process_reader(input.as_bytes());
+        let account = engine.account(2).expect("Account exists");
+        assert!(account.locked);
+        assert_eq!(account.total(), Decimal::ZERO);
+        assert_eq!(engine.open_dispute_details().len(), 0);
     }
 
     #[test]
-    fn test_disputes() {
-        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
-        engine.run();
+    fn test_allow_zero_amount() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,1,2,0\n\
+            withdrawal,1,3,0.0000\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(
+            engine
+                .error_report()
+                .group("TxInvalidAmount")
+                .unwrap()
+                .count,
+            2
+        );
 
-        // Client 1 dispute was resolved
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                allow_zero_amount: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+        assert_eq!(engine.stats().processed, 3);
+        assert!(engine.error_report().is_empty());
+        assert_eq!(engine.available(1), Some(Decimal::new(10, 0)));
+        assert_eq!(engine.history(1).len(), 3);
+
+        // Zero transfers are still rejected
+        assert!(matches!(
+            engine.process_tx(Tx::transfer(1, 2, 4, Decimal::ZERO)),
+            Err(Error::TxInvalidAmount(4))
+        ));
+    }
+
+    #[test]
+    fn test_negative_deposit_is_withdrawal() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100.00\n\
+            deposit,1,2,-30.00\n\
+            deposit,1,3,-80.00\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").available,
-            Decimal::new(10000, 2)
+            engine
+                .error_report()
+                .group("TxInvalidAmount")
+                .unwrap()
+                .count,
+            2
+        );
+
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                negative_is_withdrawal: true,
+                ..Default::default()
+            },
         );
+        engine.process_reader(input.as_bytes());
+
+        // The second withdrawal exceeds the remaining funds
+        assert_eq!(engine.stats().processed, 2);
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").held,
-            Decimal::ZERO
+            engine.error_report().group("NotEnoughFunds").unwrap().count,
+            1
         );
-        assert!(!engine.accounts.get(&1).expect("Account exists").locked);
-        assert!(engine.disputes.get(&1).is_none());
+        assert_eq!(
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(70, 0)
+        );
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 2, None)),
+            Err(Error::WithdrawalDisputesDisabled(2))
+        ));
+    }
 
-        // Client 2 dispute is still open
+    #[test]
+    fn test_emit_transactions_parity() {
+        let mut engine = PaymentsEngine::new("examples/simple_deposit.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        let mut buf = Vec::new();
+        engine.emit_transactions(&mut buf);
+
+        let parse = |input: &[u8]| {
+            ReaderBuilder::new()
+                .trim(Trim::All)
+                .flexible(true)
+                .from_reader(input)
+                .deserialize()
+                .collect::<std::result::Result<Vec<Tx>, _>>()
+                .expect("Transactions are valid CSV")
+        };
+        let input = std::fs::read("examples/simple_deposit.csv").unwrap();
+        assert_eq!(parse(&buf), parse(&input));
         assert_eq!(
-            engine.accounts.get(&2).expect("Account exists").available,
-            Decimal::ZERO
+            String::from_utf8(buf).unwrap(),
+            "type,client,tx,amount,dest,timestamp\ndeposit,1,1,100.1001,,\n"
         );
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            adjustment,1,2,-30.5\n\
+            adjustment,1,3,10\n\
+            adjustment,1,4,-80\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // The last adjustment would drive the available funds negative
+        assert_eq!(engine.stats().adjustments, 2);
+        assert_eq!(engine.stats().processed, 3);
+        assert_eq!(engine.stats().rejected, 1);
         assert_eq!(
-            engine.accounts.get(&2).expect("Account exists").held,
-            Decimal::new(10000, 2)
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(795, 1)
         );
-        assert!(engine.disputes.get(&2).is_some());
+    }
 
-        // Client 3 resolve ignored since no dispute opened
+    #[test]
+    fn test_hold_and_unhold() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            hold,1,2,30\n\
+            hold,1,3,80\n\
+            dispute,1,1,\n\
+            unhold,1,4,40\n\
+            resolve,1,1,\n\
+            unhold,1,5,10\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // The second hold exceeds the available funds and the first unhold exceeds the holds,
+        // even though the dispute holds more
+        assert_eq!(engine.stats().rejected, 2);
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.available, Decimal::new(80, 0));
+        assert_eq!(account.held, Decimal::new(20, 0));
+        assert_eq!(account.admin_held(), Decimal::new(20, 0));
+        engine.check_invariants().expect("Invariants hold");
+
+        engine
+            .process_tx(Tx::new(TxType::Unhold, 1, 6, Some(Decimal::new(20, 0))))
+            .expect("Unhold is valid");
+        assert_eq!(engine.available(1), Some(Decimal::new(100, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::ZERO));
+
+        engine.accounts.get_mut(&1).unwrap().admin_held = Decimal::ONE;
+        assert!(matches!(
+            engine.check_invariants(),
+            Err(Error::InvariantViolation(
+                1,
+                "held funds do not match the open disputes"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_unparseable_amount() {
+        let input = "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,3,abc\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().malformed, 0);
+        assert_eq!(engine.stats().rejected, 1);
         assert_eq!(
-            engine.accounts.get(&3).expect("Account exists").available,
-            Decimal::new(10000, 2)
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(100, 0)
+        );
+
+        let reader = csv_reader(input.as_bytes(), &EngineConfig::default());
+        let ids = IdFormat::from(&EngineConfig::default());
+        let raw = csv_records(reader, ids).nth(1).unwrap().1.unwrap();
+        assert!(matches!(engine.parse_tx(raw), Err(Error::AmountParse(3))));
+    }
+
+    #[test]
+    fn test_amount_scale_rounding() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,0.005\n\
+            deposit,1,2,0.015\n\
+            deposit,1,3,1.25\n\
+            deposit,1,4,0.0249\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                amount_scale: Some(2),
+                ..Default::default()
+            },
         );
+        engine.process_reader(input.as_bytes());
+
+        // Midpoints are rounded to the even neighbour: 0.00, 0.02, 1.25 and 0.02
+        let stats = engine.stats();
+        assert_eq!(stats.rounding_events, 3);
+        assert_eq!(stats.rounding_residual, Decimal::new(149, 4));
         assert_eq!(
-            engine.accounts.get(&3).expect("Account exists").held,
-            Decimal::ZERO
+            engine.account(1).expect("Account exists").available,
+            Decimal::new(129, 2)
         );
-        assert!(engine.disputes.get(&3).is_none());
     }
 
     #[test]
-    fn test_reversed_deposit() {
-        let mut engine = PaymentsEngine::new("examples/reversed_deposit.csv".to_string());
-        engine.run();
+    fn test_decimal_comma() {
+        let input = "type;client;tx;amount\n\
+                     deposit;1;1;50,00\n\
+                     withdrawal;1;2;0,5\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                decimal_comma: true,
+                delimiter: b';',
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.txs[&1].amount, Some(Decimal::new(5000, 2)));
+        assert_eq!(engine.available(1), Some(Decimal::new(495, 1)));
+    }
+
+    #[test]
+    fn test_snap_amounts() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,50.0000000000001\n\
+                     deposit,1,2,1.00001\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                snap_epsilon: Some(Decimal::new(1, 9)),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
 
-        // Deposit was reversed and the deposit following the chargeback was ignored
-        assert!(engine.accounts.get(&1).expect("Account exists").locked);
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").available,
-            Decimal::ZERO
+            engine.available(1).map(|a| a.to_string()),
+            Some("50.0000".to_string())
         );
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").held,
-            Decimal::ZERO
+            engine
+                .error_report()
+                .group("ImpreciseAmount")
+                .map(|g| g.count),
+            Some(1)
         );
-        assert_eq!(engine.txs.len(), 0);
     }
 
     #[test]
-    fn test_whitespace() {
-        let mut engine = PaymentsEngine::new("examples/whitespace.csv".to_string());
-        engine.run();
-        assert_eq!(engine.accounts.len(), 1);
+    fn test_round_input() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.00005\n\
+            withdrawal,1,2,0.00004\n\
+            dispute,1,1,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                round_input: Some(4),
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The midpoint is rounded up, and the withdrawal rounded to zero is rejected
+        assert_eq!(engine.stats().rejected, 1);
+        assert_eq!(engine.available(1), Some(Decimal::ZERO));
+        assert_eq!(engine.held(1), Some(Decimal::new(10001, 4)));
+        assert_eq!(engine.history(1)[0].amount(), Some(Decimal::new(10001, 4)));
+    }
+
+    #[test]
+    fn test_normalize_stored_amounts() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,50.00\n\
+            deposit,1,2,50\n\
+            dispute,1,1,\n\
+            dispute,1,2,\n";
+        let run = |normalize_stored_amounts| {
+            let mut engine = PaymentsEngine::with_config(
+                String::new(),
+                EngineConfig {
+                    normalize_stored_amounts,
+                    ..Default::default()
+                },
+            );
+            engine.process_reader(input.as_bytes());
+            engine
+        };
+
+        // The amounts are equal either way, only their representation differs
+        let engine = run(false);
+        assert_eq!(engine.history(1)[0].amount().unwrap().to_string(), "50.00");
+        assert_eq!(engine.held(1), Some(Decimal::new(100, 0)));
+
+        let mut engine = run(true);
+        let amounts: Vec<_> = engine
+            .history(1)
+            .iter()
+            .map(|tx| tx.amount().unwrap().to_string())
+            .collect();
+        assert_eq!(amounts, ["50", "50"]);
+        let held = engine.held(1).unwrap();
+        assert_eq!((held, held.scale()), (Decimal::new(100, 0), 0));
+        engine
+            .process_tx(Tx::new(TxType::Resolve, 1, 1, None))
+            .expect("Resolve succeeds");
+        assert_eq!(engine.available(1), Some(Decimal::new(50, 0)));
+        assert_eq!(engine.held(1), Some(Decimal::new(50, 0)));
+        engine.check_invariants().expect("Invariants hold");
+    }
+
+    #[test]
+    fn test_refund() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            deposit,1,2,30\n\
+            withdrawal,1,3,70\n\
+            refund,1,2,\n\
+            refund,1,1,\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        // Refunding the first deposit would overdraw the account
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.available, Decimal::new(30, 0));
+        assert!(!account.locked);
         assert_eq!(
-            engine.accounts.get(&1).expect("Account exists").available,
-            Decimal::new(9000, 2)
+            engine
+                .error_report()
+                .group("NotEnoughFunds")
+                .unwrap()
+                .example_tx_ids,
+            vec![1]
+        );
+
+        // The refund is retained in place of the deposit
+        assert_eq!(
+            engine.txs.get(&2).expect("Refund is retained").ty,
+            TxType::Refund
         );
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Dispute, 1, 2, None)),
+            Err(Error::OriginalTxNotDeposit(TxType::Dispute, 2))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Refund, 1, 2, None)),
+            Err(Error::OriginalTxNotDeposit(TxType::Refund, 2))
+        ));
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Refund, 1, 9, None)),
+            Err(Error::TxDoesNotExist(9))
+        ));
+
+        // Further deposits are accepted
+        engine
+            .process_tx(Tx::new(TxType::Deposit, 1, 4, Some(Decimal::ONE)))
+            .expect("Deposit succeeds");
     }
 
     #[test]
-    fn test_print_accounts() {
-        let mut engine = PaymentsEngine::new("examples/simple_deposit.csv".to_string());
-        engine.run();
+    fn test_unknown_tx_type() {
+        let input = "type,client,tx,amount\ndeposit,1,1,10\nbonus,1,2,5\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().malformed, 0);
+        assert_eq!(engine.stats().rejected, 1);
+        let group = engine.error_report().group("UnknownTxType").unwrap();
+        assert_eq!(group.example_tx_ids, vec![2]);
+        assert!(matches!(
+            engine.process_tx(Tx::new(TxType::Unknown("bonus".to_string()), 1, 3, None)),
+            Err(Error::UnknownTxType(name, 3)) if name == "bonus"
+        ));
+        // No account is created for an unknown type
+        assert!(engine
+            .process_tx(Tx::new(TxType::Unknown("bonus".to_string()), 2, 4, None))
+            .is_err());
+        assert!(engine.account(2).is_none());
+    }
+
+    #[test]
+    fn test_error_report_json() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,50\n\
+            withdrawal,1,2,100\n\
+            withdrawal,1,3,60\n\
+            resolve,1,1,\n";
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
 
         let mut buf = Vec::new();
-        engine.print_accounts(&mut buf);
+        engine.error_report().write_json(&mut buf).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "NotEnoughFunds": { "count": 2, "example_tx_ids": [2, 3] },
+                "TxNotUnderDispute": { "count": 1, "example_tx_ids": [1] },
+            })
+        );
+    }
 
-        let expected = "client,available,held,total,locked\n1,100.1001,0,100.1001,false\n";
+    #[test]
+    fn test_ignore_stray_resolves() {
+        let mut engine = PaymentsEngine::with_config(
+            "examples/disputes.csv".to_string(),
+            EngineConfig {
+                ignore_stray_resolves: true,
+                ..Default::default()
+            },
+        );
+        engine.run().expect("Input is processed");
+
+        // Client 3 resolve is ignored since no dispute was opened
+        assert_eq!(engine.stats().rejected, 0);
+        assert_eq!(engine.stats().stray_resolves, 1);
+        assert!(engine.error_report().is_empty());
+        let account = engine.account(3).expect("Account exists");
+        assert_eq!(account.available, Decimal::new(100, 0));
+        assert_eq!(account.held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_accrue_penalties() {
+        let input = "type,client,tx,amount,dest,timestamp\n\
+            deposit,1,1,100,,0\n\
+            deposit,1,2,50,,0\n\
+            deposit,1,3,5,,0\n\
+            deposit,2,4,10,,0\n\
+            dispute,1,1,,,86400\n\
+            dispute,1,2,,,864000\n\
+            dispute,2,4,,,0\n";
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-penalties-{}.jsonl",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).expect("Audit log is created");
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                penalty_threshold_days: 3,
+                ..Default::default()
+            },
+        )
+        .with_clock(SequenceClock::starting_at(1))
+        .with_audit_writer(file);
+        engine.process_reader(input.as_bytes());
+        let day = |days| UNIX_EPOCH + Duration::from_secs(days * SECONDS_PER_DAY);
+
+        assert!(matches!(
+            engine.accrue_penalties(Decimal::new(-1, 3), day(11)),
+            Err(Error::NegativePenaltyRate(_))
+        ));
+        // No penalty of a client is charged unless the available funds cover all of them
+        let failures = engine
+            .accrue_penalties(Decimal::ONE, day(11))
+            .expect("Rate is valid");
+        assert!(matches!(
+            failures.as_slice(),
+            [(1, Error::NotEnoughFunds(1)), (2, Error::NotEnoughFunds(4))]
+        ));
+        assert_eq!(engine.available(1), Some(Decimal::new(5, 0)));
+
+        // Tx 1 has been disputed for 10 days, tx 2 for 1 day which is under the threshold, and
+        // client 2 has no available funds left
+        let failures = engine
+            .accrue_penalties(Decimal::new(1, 3), day(11))
+            .expect("Rate is valid");
+        assert!(matches!(
+            failures.as_slice(),
+            [(2, Error::NotEnoughFunds(4))]
+        ));
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.available, Decimal::new(4, 0));
+        assert_eq!(account.held, Decimal::new(150, 0));
+
+        // The days already charged aren't charged again
+        engine
+            .accrue_penalties(Decimal::new(1, 3), day(11))
+            .expect("Rate is valid");
+        assert_eq!(engine.available(1), Some(Decimal::new(4, 0)));
+        engine
+            .accrue_penalties(Decimal::new(1, 3), day(12))
+            .expect("Rate is valid");
+        assert_eq!(engine.available(1), Some(Decimal::new(39, 1)));
+
+        let audit = std::fs::read_to_string(&path).expect("Audit log is written");
+        std::fs::remove_file(&path).expect("Audit log is removed");
+        let penalties: Vec<AuditRecord> = audit
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Audit record is valid"))
+            .filter(|record: &AuditRecord| record.ty == TxType::Penalty)
+            .collect();
+        assert_eq!(penalties.len(), 2);
+        assert!(penalties.iter().all(|penalty| penalty.tx == 1));
+        assert_eq!(penalties[0].amount, Some(Decimal::ONE));
+        assert_eq!(penalties[0].available_before, Decimal::new(5, 0));
+        assert_eq!(penalties[0].available_after, Decimal::new(4, 0));
+        assert_eq!(penalties[0].seq, 8);
+        assert_eq!(penalties[1].amount, Some(Decimal::new(1, 1)));
+        assert_eq!(penalties[1].seq, 9);
+    }
+
+    #[test]
+    fn test_with_config() {
+        let config = EngineConfig {
+            ignore_stray_resolves: true,
+            max_open_disputes_per_client: Some(1),
+            ..Default::default()
+        };
+        assert_ne!(config, EngineConfig::default());
+        let mut engine =
+            PaymentsEngine::with_config("examples/disputes.csv".to_string(), config.clone());
+        engine.run().expect("Input is processed");
+
+        assert_eq!(engine.config(), &config);
+        assert_eq!(engine.stats().rejected, 0);
+        assert_eq!(engine.stats().stray_resolves, 1);
+    }
+
+    #[test]
+    fn test_overflow_is_an_error() {
+        let input = format!(
+            "type,client,tx,amount\n\
+            deposit,1,1,{max}\n\
+            deposit,1,2,{max}\n\
+            dispute,1,1,\n\
+            deposit,1,3,{max}\n\
+            adjustment,1,4,1\n",
+            max = Decimal::MAX
+        );
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+
+        assert_eq!(engine.stats().processed, 2);
+        assert_eq!(engine.error_report().group("Overflow").unwrap().count, 3);
+        engine.check_invariants().expect("Invariants hold");
+        assert_eq!(engine.summaries()[0].total, Decimal::MAX);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        for example in [
+            "examples/deposits_and_withdrawals.csv",
+            "examples/disputes.csv",
+            "examples/reversed_deposit.csv",
+        ] {
+            let mut engine = PaymentsEngine::new(example.to_string());
+            engine.run().expect("Input is processed");
+            engine.check_invariants().expect("Invariants hold");
+        }
+
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+        engine
+            .accounts
+            .get_mut(&2)
+            .expect("Account exists")
+            .open_disputes = 0;
+        assert!(matches!(
+            engine.check_invariants(),
+            Err(Error::InvariantViolation(2, _))
+        ));
+    }
+
+    #[test]
+    fn test_check_invariants_held_funds() {
+        let mut engine = PaymentsEngine::new("examples/disputes.csv".to_string());
+        engine.run().expect("Input is processed");
+
+        // The funds held by client 2 match its single open dispute
+        let disputes = engine.open_dispute_details();
+        assert_eq!(disputes.len(), 1);
+        assert_eq!(disputes[0].client, 2);
+        assert_eq!(
+            disputes[0].held,
+            engine.account(2).expect("Account exists").held
+        );
+        engine.check_invariants().expect("Invariants hold");
+
+        // Funds held without a dispute are reported
+        engine.accounts.get_mut(&1).expect("Account exists").held = Decimal::ONE;
+        assert!(matches!(
+            engine.check_invariants(),
+            Err(Error::InvariantViolation(
+                1,
+                "held funds do not match the open disputes"
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_explain() {
+        #[derive(Clone, Default)]
+        struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let mut engine = PaymentsEngine::new("examples/failed_withdrawal.csv".to_string())
+            .with_explain_writer(buf.clone());
+        engine.run().expect("Input is processed");
+
+        let explanation = String::from_utf8(buf.0.borrow().clone()).unwrap();
+        assert_eq!(
+            explanation,
+            "Applied deposit of 50 for client 1; available 50, held 0.\n\
+            Rejected withdrawal of 100 for client 1: Client does not have enough funds to \
+            perform the transaction (id: 2).\n"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_flag() {
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+        // Set the flag while the third record is read, as a signal handler would
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,10\n\
+            deposit,2,2,20\n\
+            deposit,3,3,30\n\
+            deposit,4,4,40\n";
+        let mut reader = csv_reader(input.as_bytes(), &EngineConfig::default());
+        let headers = reader.byte_headers().unwrap().clone();
+        let records = csv_records(reader, IdFormat::from(&EngineConfig::default()))
+            .enumerate()
+            .map(|(i, record)| {
+                if i == 2 {
+                    INTERRUPTED.store(true, Ordering::Relaxed);
+                }
+                record
+            });
+        let mut engine = PaymentsEngine::new(String::new()).with_interrupt_flag(&INTERRUPTED);
+        engine.process_records(&headers, records);
+        assert!(engine.is_interrupted());
+
+        let mut buf = Vec::new();
+        engine.print_accounts(&mut buf);
+        let expected = "client,available,held,total,locked\n\
+            1,10,0,10,false\n\
+            2,20,0,20,false\n";
         assert_eq!(String::from_utf8(buf).unwrap(), expected);
     }
+
+    #[test]
+    fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).expect("Audit log is created");
+        let mut engine = PaymentsEngine::new(String::new())
+            .with_clock(SequenceClock::starting_at(1))
+            .with_audit_writer(file);
+        let input = "type,client,tx,amount,dest\n\
+            deposit,1,1,50,\n\
+            withdrawal,1,2,100,\n\
+            transfer,1,3,20,2\n\
+            dispute,1,1,,\n";
+        engine.process_reader(input.as_bytes());
+
+        let audit = std::fs::read_to_string(&path).expect("Audit log is written");
+        std::fs::remove_file(&path).expect("Audit log is removed");
+        let records: Vec<AuditRecord> = audit
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Audit record is valid"))
+            .collect();
+        // The rejected withdrawal is not audited, the transfer changes two accounts
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| {
+                (
+                    r.client,
+                    r.tx,
+                    r.available_before,
+                    r.available_after,
+                    r.held_after,
+                )
+            })
+            .collect();
+        let dec = |n| Decimal::new(n, 0);
+        assert_eq!(
+            summary,
+            vec![
+                (1, 1, dec(0), dec(50), dec(0)),
+                (1, 3, dec(50), dec(30), dec(0)),
+                (2, 3, dec(0), dec(20), dec(0)),
+                (1, 1, dec(30), dec(-20), dec(50)),
+            ]
+        );
+        assert_eq!(records[3].ty, TxType::Dispute);
+        assert_eq!(records[3].seq, 4);
+        assert_eq!(
+            audit.lines().next().unwrap(),
+            "{\"seq\":1,\"client\":1,\"tx\":1,\"type\":\"deposit\",\"amount\":\"50\",\
+            \"available_before\":\"0\",\"held_before\":\"0\",\"available_after\":\"50\",\
+            \"held_after\":\"0\"}"
+        );
+    }
+
+    #[test]
+    fn test_audit_log_ignores_dest_of_other_types() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-audit-dest-{}.jsonl",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).expect("Audit log is created");
+        let mut engine = PaymentsEngine::new(String::new()).with_audit_writer(file);
+        let input = "type,client,tx,amount,dest\n\
+            deposit,1,1,10.0,5\n\
+            withdrawal,1,2,4,5\n";
+        engine.process_reader(input.as_bytes());
+
+        let audit = std::fs::read_to_string(&path).expect("Audit log is written");
+        std::fs::remove_file(&path).expect("Audit log is removed");
+        let clients: Vec<_> = audit
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<AuditRecord>(line)
+                    .expect("Audit record is valid")
+                    .client
+            })
+            .collect();
+        assert_eq!(clients, [1, 1]);
+        assert!(engine.account(5).is_none());
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let path = std::env::temp_dir().join(format!(
+            "payments-engine-test-dead-letter-{}.csv",
+            std::process::id()
+        ));
+        let file = std::fs::File::create(&path).expect("Dead-letter file is created");
+        let mut engine = PaymentsEngine::new(String::new()).with_dead_letter_writer(file);
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,50\n\
+            withdrawal,1,2,100\n\
+            deposit,x,3,1\n\
+            withdrawal,1,4,20\n";
+        engine.process_reader(input.as_bytes());
+
+        let dead_letter = std::fs::read_to_string(&path).expect("Dead-letter file is written");
+        std::fs::remove_file(&path).expect("Dead-letter file is removed");
+        let rows: Vec<_> = dead_letter.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], "type,client,tx,amount,error");
+        assert_eq!(
+            rows[1],
+            format!("withdrawal,1,2,100,{}", Error::NotEnoughFunds(2))
+        );
+        assert!(rows[2].starts_with("deposit,x,3,1,"));
+        assert_eq!(engine.available(1), Some(Decimal::new(30, 0)));
+
+        // The JSON lines are written whole
+        let file = std::fs::File::create(&path).expect("Dead-letter file is created");
+        let mut engine = PaymentsEngine::new(String::new()).with_dead_letter_writer(file);
+        let input = "{\"type\":\"withdrawal\",\"client\":1,\"tx\":1,\"amount\":\"5\"}\n";
+        engine.process_jsonl_reader(input.as_bytes());
+
+        let dead_letter = std::fs::read_to_string(&path).expect("Dead-letter file is written");
+        std::fs::remove_file(&path).expect("Dead-letter file is removed");
+        let rows: Vec<_> = dead_letter.lines().collect();
+        assert_eq!(rows[0], "record,error");
+        assert_eq!(
+            rows[1],
+            format!(
+                "\"{{\"\"type\"\":\"\"withdrawal\"\",\"\"client\"\":1,\"\"tx\"\":1,\"\"amount\"\":\"\"5\"\"}}\",{}",
+                Error::NotEnoughFunds(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_disputes() {
+        let config = EngineConfig {
+            withdrawal_disputes: true,
+            ..Default::default()
+        };
+        let input = "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,40\n";
+        let balances = |engine: &PaymentsEngine| {
+            let account = engine.account(1).expect("Account exists");
+            (
+                account.available,
+                account.held,
+                account.total(),
+                account.locked,
+            )
+        };
+        let dec = |n| Decimal::new(n, 0);
+
+        let mut engine = PaymentsEngine::with_config(String::new(), config.clone());
+        engine.process_reader(input.as_bytes());
+        assert_eq!(balances(&engine), (dec(60), dec(0), dec(60), false));
+
+        // Dispute: the withdrawn funds are held pending their return
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 2, None))
+            .expect("Dispute succeeds");
+        assert_eq!(balances(&engine), (dec(60), dec(40), dec(100), false));
+
+        // Resolve: the withdrawal stands, so the held funds are released
+        engine
+            .process_tx(Tx::new(TxType::Resolve, 1, 2, None))
+            .expect("Resolve succeeds");
+        assert_eq!(balances(&engine), (dec(60), dec(0), dec(60), false));
+
+        // Chargeback: the withdrawn funds are returned to the client
+        engine
+            .process_tx(Tx::new(TxType::Dispute, 1, 2, None))
+            .expect("Dispute succeeds");
+        engine
+            .process_tx(Tx::new(TxType::ChargeBack, 1, 2, None))
+            .expect("Chargeback succeeds");
+        assert_eq!(balances(&engine), (dec(100), dec(0), dec(100), true));
+        engine.check_invariants().expect("Invariants hold");
+
+        // Withdrawals can't be disputed, resolved or charged back unless enabled
+        let mut engine = PaymentsEngine::new(String::new());
+        engine.process_reader(input.as_bytes());
+        for ty in [TxType::Dispute, TxType::Resolve, TxType::ChargeBack] {
+            assert!(matches!(
+                engine.process_tx(Tx::new(ty, 1, 2, None)),
+                Err(Error::WithdrawalDisputesDisabled(2))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_already_under_dispute() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,100\n\
+            withdrawal,1,2,40\n\
+            dispute,1,2,\n\
+            dispute,1,2,\n";
+        let mut engine = PaymentsEngine::with_config(
+            String::new(),
+            EngineConfig {
+                withdrawal_disputes: true,
+                ..Default::default()
+            },
+        );
+        engine.process_reader(input.as_bytes());
+
+        // The second dispute is rejected and doesn't hold the funds again
+        let group = engine
+            .error_report()
+            .group("TxAlreadyUnderDispute")
+            .expect("Second dispute is rejected");
+        assert_eq!(group.example_tx_ids, vec![2]);
+        let account = engine.account(1).expect("Account exists");
+        assert_eq!(account.held, Decimal::new(40, 0));
+        assert_eq!(account.open_disputes, 1);
+        engine.check_invariants().expect("Invariants hold");
+    }
 }
@@ -0,0 +1,94 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rust_decimal::Decimal;
+
+use crate::engine::{Tx, TxType};
+
+// Number of distinct clients in the generated transactions
+const CLIENTS: u16 = 100;
+
+// A deposit that can still be disputed
+struct Deposit {
+    client: u16,
+    id: u32,
+    amount: Decimal,
+}
+
+/// Generate a reproducible stream of `count` valid transactions from `seed`
+///
+/// The stream mixes deposits, withdrawals that never exceed the available funds, disputes of
+/// earlier deposits and resolves of the open disputes. Chargebacks are left out, as they lock
+/// the accounts. The same seed always yields the same stream with a given `rand` version.
+pub fn generate_transactions(seed: u64, count: usize) -> Vec<Tx> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut available = vec![Decimal::ZERO; usize::from(CLIENTS) + 1];
+    let mut deposits: Vec<Deposit> = Vec::new();
+    let mut disputes: Vec<Deposit> = Vec::new();
+    let mut next_id = 1;
+    let mut txs = Vec::with_capacity(count);
+    while txs.len() < count {
+        let client = rng.gen_range(1..=CLIENTS);
+        let balance = &mut available[usize::from(client)];
+        let tx = match rng.gen_range(0..100) {
+            0..=9 if !deposits.is_empty() => {
+                let deposit = deposits.swap_remove(rng.gen_range(0..deposits.len()));
+                available[usize::from(deposit.client)] -= deposit.amount;
+                let tx = Tx::new(TxType::Dispute, deposit.client, deposit.id, None);
+                disputes.push(deposit);
+                tx
+            }
+            10..=14 if !disputes.is_empty() => {
+                let dispute = disputes.swap_remove(rng.gen_range(0..disputes.len()));
+                available[usize::from(dispute.client)] += dispute.amount;
+                let tx = Tx::new(TxType::Resolve, dispute.client, dispute.id, None);
+                deposits.push(dispute);
+                tx
+            }
+            15..=44 if *balance > Decimal::ZERO => {
+                let amount = (*balance * Decimal::new(rng.gen_range(1..=100), 2)).round_dp(4);
+                if amount.is_zero() {
+                    continue;
+                }
+                *balance -= amount;
+                next_id += 1;
+                Tx::new(TxType::Withdrawal, client, next_id - 1, Some(amount))
+            }
+            _ => {
+                let amount = Decimal::new(rng.gen_range(1..=10_000_000), 4);
+                *balance += amount;
+                deposits.push(Deposit {
+                    client,
+                    id: next_id,
+                    amount,
+                });
+                next_id += 1;
+                Tx::new(TxType::Deposit, client, next_id - 1, Some(amount))
+            }
+        };
+        txs.push(tx);
+    }
+    txs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentsEngine;
+
+    #[test]
+    fn test_generate_transactions() {
+        let txs = generate_transactions(42, 1000);
+        assert_eq!(txs.len(), 1000);
+        assert_eq!(txs, generate_transactions(42, 1000));
+        assert_ne!(txs, generate_transactions(43, 1000));
+
+        // Every generated transaction is accepted by the engine
+        let mut engine = PaymentsEngine::new(String::new());
+        for tx in txs {
+            engine
+                .process_tx(tx)
+                .expect("Generated transaction is valid");
+        }
+        engine.check_invariants().expect("Invariants hold");
+    }
+}
@@ -0,0 +1,27 @@
+mod builder;
+mod clock;
+mod config;
+mod diff;
+mod engine;
+#[cfg(feature = "test-utils")]
+mod generate;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod progress;
+mod report;
+mod store;
+
+pub use builder::PaymentsEngineBuilder;
+pub use clock::{Clock, SequenceClock, SystemClock};
+pub use config::{
+    DustAction, EngineConfig, LabelPolicy, LockedTxBehavior, OutputColumn, TrimPolicy,
+};
+pub use diff::{compare_policies, diff_summaries, read_summaries, AccountDiff};
+pub use engine::{
+    run_str, Account, AccountSummary, AuditRecord, DisputeInfo, EngineSnapshot, Error, InputFormat,
+    OutputFormat, PaymentsEngine, Result, RunReport, Stats, Tx, TxType,
+};
+#[cfg(feature = "test-utils")]
+pub use generate::generate_transactions;
+pub use report::{ErrorGroup, ErrorReport};
+pub use store::Store;
@@ -1,17 +1,687 @@
-use clap::Parser;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-mod engine;
-use engine::PaymentsEngine;
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_decimal::Decimal;
+
+use payments_engine::{
+    diff_summaries, read_summaries, DustAction, EngineConfig, EngineSnapshot, Error, LabelPolicy,
+    LockedTxBehavior, OutputColumn, OutputFormat, PaymentsEngine, TrimPolicy,
+};
+
+// Set by the SIGINT handler, so that the accounts processed so far can still be printed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(index = 1, help = "Path to CSV file containing transactions")]
-    input_file: String,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(
+        index = 1,
+        required = true,
+        help = "Path to CSV file containing transactions, or to a directory of CSV files"
+    )]
+    input_file: Option<String>,
+    #[clap(
+        long,
+        help = "Capacity in bytes of the buffer used to read the input file"
+    )]
+    read_buffer_bytes: Option<usize>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Abort the run once more than N records were rejected or malformed"
+    )]
+    max_errors: Option<u64>,
+    #[clap(
+        long,
+        requires = "max_errors",
+        help = "Still print the accounts processed so far when the run is aborted by --max-errors"
+    )]
+    partial_output_on_abort: bool,
+    #[clap(
+        long,
+        help = "Check the balances of the accounts after every transaction and abort on the first inconsistency"
+    )]
+    check_invariants_each_step: bool,
+    #[clap(
+        long,
+        help = "Only process every N-th record (the result is approximate)"
+    )]
+    sample_rate: Option<NonZeroUsize>,
+    #[clap(long, value_name = "N", help = "Parse the CSV input with N threads")]
+    parse_threads: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        value_name = "PLACES",
+        help = "Round amounts to the given number of decimal places"
+    )]
+    amount_scale: Option<u32>,
+    #[clap(
+        long,
+        help = "Snap amounts within --snap-epsilon of a 4 decimal place value to it, rejecting the others"
+    )]
+    snap_amounts: bool,
+    #[clap(
+        long,
+        value_name = "DECIMAL",
+        default_value = "0.000000001",
+        help = "Largest distance to a 4 decimal place value that is snapped, for --snap-amounts"
+    )]
+    snap_epsilon: Decimal,
+    #[clap(long, help = "Warn about amounts that lose precision when rounded")]
+    warn_on_rounding: bool,
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with = "amount_scale",
+        help = "Round the amounts of the transactions to N decimal places (half up) before applying them"
+    )]
+    round_input: Option<u32>,
+    #[clap(
+        long,
+        help = "Strip the trailing zeros of the amounts retained for disputes"
+    )]
+    normalize_stored_amounts: bool,
+    #[clap(
+        long,
+        help = "Maximum number of disputes a client may have open at once"
+    )]
+    max_open_disputes_per_client: Option<usize>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Reject transactions that would create more than N accounts"
+    )]
+    max_accounts: Option<usize>,
+    #[clap(
+        long,
+        value_name = "DECIMAL",
+        help = "Maximum funds a client may withdraw per UTC day, for timestamped withdrawals"
+    )]
+    daily_withdrawal_limit: Option<Decimal>,
+    #[clap(
+        long,
+        value_name = "TS",
+        help = "Skip the records with a Unix timestamp before TS"
+    )]
+    from: Option<u64>,
+    #[clap(
+        long,
+        value_name = "TS",
+        help = "Skip the records with a Unix timestamp at or after TS"
+    )]
+    to: Option<u64>,
+    #[clap(
+        long,
+        value_name = "CHANNEL",
+        value_delimiter = ',',
+        help = "Only process the records from these channels, e.g. web,api"
+    )]
+    channels: Option<Vec<String>>,
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = clap::ArgAction::Set,
+        default_value_t = true,
+        help = "Accept withdrawals leaving available funds below --dust-threshold"
+    )]
+    allow_dust: bool,
+    #[clap(
+        long,
+        value_name = "DECIMAL",
+        default_value = "0.01",
+        help = "Available funds below which a balance is dust, with --allow-dust false"
+    )]
+    dust_threshold: Decimal,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Dust::Reject,
+        help = "Whether withdrawals leaving dust are rejected or also withdraw the dust"
+    )]
+    dust_action: Dust,
+    #[clap(
+        long,
+        help = "Skip transactions for locked accounts without reporting an error"
+    )]
+    skip_locked: bool,
+    #[clap(
+        long,
+        conflicts_with = "skip_locked",
+        help = "Skip transactions for locked accounts, except disputes, resolves and chargebacks"
+    )]
+    replay: bool,
+    #[clap(
+        long,
+        help = "Ignore resolves and chargebacks of undisputed transactions"
+    )]
+    ignore_stray_resolves: bool,
+    #[clap(
+        long,
+        value_name = "STREAM",
+        help = "Print a JSON summary of the errors to the given stream at the end of the run"
+    )]
+    print_errors_json: Option<Stream>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Also write the accounts as JSON to the given file"
+    )]
+    json_output: Option<String>,
+    #[cfg(feature = "msgpack")]
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Also write the accounts as MessagePack to the given file"
+    )]
+    msgpack_output: Option<String>,
+    #[clap(
+        long,
+        help = "Reject the whole input, printing no accounts, if any record can't be parsed"
+    )]
+    all_or_nothing: bool,
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "checkpoint_path",
+        help = "Write a checkpoint of the engine state every N records"
+    )]
+    checkpoint_every: Option<NonZeroUsize>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "File the checkpoints are written to, resumed from if it exists"
+    )]
+    checkpoint_path: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Append a JSON line to the given file for every account change"
+    )]
+    audit_log: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Show the share of the input read so far on stderr, if it is a terminal"
+    )]
+    progress: bool,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Write the rejected records to the given CSV file, with the reason of the rejection"
+    )]
+    dead_letter: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Drop the retained withdrawals every N records to reclaim memory"
+    )]
+    compact_every: Option<NonZeroUsize>,
+    #[clap(long, help = "Narrate the effect of every record on stderr")]
+    explain: bool,
+    #[clap(long, help = "Allow withdrawals to be disputed")]
+    withdrawal_disputes: bool,
+    #[clap(
+        long,
+        help = "Reject disputes, resolves and chargebacks that specify an amount"
+    )]
+    strict_dispute_rows: bool,
+    #[clap(
+        long,
+        help = "Hold disputes of transactions not seen yet until they arrive"
+    )]
+    buffer_orphan_disputes: bool,
+    #[clap(long, help = "End the output lines with CRLF instead of LF")]
+    crlf: bool,
+    #[clap(long, help = "Print the funds as integers of minor units (e.g. cents)")]
+    output_minor_units: bool,
+    #[clap(
+        long,
+        value_name = "PLACES",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u32).range(0..=18),
+        help = "Number of decimal places per major unit, for --output-minor-units"
+    )]
+    minor_unit_places: u32,
+    #[clap(long, help = "Only print the accounts with negative available funds")]
+    negative_only: bool,
+    #[clap(
+        long,
+        help = "Append a row with the client `*` summing the funds of all accounts"
+    )]
+    grand_total: bool,
+    #[clap(
+        long,
+        help = "Reject deposits and withdrawals reusing the id of an earlier one"
+    )]
+    forbid_id_reuse: bool,
+    #[clap(
+        long,
+        help = "Accept deposits and withdrawals of a zero amount as no-ops"
+    )]
+    allow_zero_amount: bool,
+    #[clap(long, help = "Treat deposits with a negative amount as withdrawals")]
+    negative_is_withdrawal: bool,
+    #[clap(long, help = "Append the client_name of each client to the output")]
+    with_label: bool,
+    #[clap(
+        long,
+        help = "Label clients with their latest client_name instead of the first one"
+    )]
+    latest_label: bool,
+    #[clap(
+        long,
+        value_name = "CHAR",
+        value_parser = parse_delimiter,
+        help = "Delimiter of the CSV input [default: `;` with --decimal-comma, `,` otherwise]"
+    )]
+    delimiter: Option<u8>,
+    #[clap(
+        long,
+        help = "Parse amounts with a comma as the decimal separator (e.g. 50,00)"
+    )]
+    decimal_comma: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Trim::All,
+        help = "Whitespace trimmed from the input"
+    )]
+    trim: Trim,
+    #[clap(
+        long,
+        help = "Trim the whitespace around the client and tx ids, even with --trim none"
+    )]
+    normalize_whitespace_in_ids: bool,
+    #[clap(
+        long,
+        help = "Accept hex (0x-prefixed) and underscore-separated client and tx ids"
+    )]
+    lenient_ids: bool,
+    #[clap(
+        long,
+        value_name = "PLACES",
+        help = "Round the printed totals to the given number of decimal places"
+    )]
+    total_scale: Option<u32>,
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        help = "Columns of the output, in order"
+    )]
+    columns: Option<Vec<Column>>,
+    #[clap(
+        long,
+        value_name = "NAME=COLUMN",
+        value_delimiter = ',',
+        value_parser = parse_rename,
+        help = "Rename columns in the output header, e.g. account=client,avail=available"
+    )]
+    rename_columns: Vec<(String, Column)>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Report the clients whose accounts differ between two account outputs
+    Diff {
+        #[clap(help = "Path to the first account CSV file")]
+        first: String,
+        #[clap(help = "Path to the second account CSV file")]
+        second: String,
+    },
+    /// Print a reproducible stream of synthetic transactions as CSV
+    #[cfg(feature = "test-utils")]
+    Generate {
+        #[clap(long, help = "Seed of the random generator")]
+        seed: u64,
+        #[clap(long, help = "Number of transactions to generate")]
+        count: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Dust {
+    Reject,
+    Sweep,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Trim {
+    None,
+    Headers,
+    Fields,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Label,
+}
+
+impl From<Column> for OutputColumn {
+    fn from(column: Column) -> Self {
+        match column {
+            Column::Client => OutputColumn::Client,
+            Column::Available => OutputColumn::Available,
+            Column::Held => OutputColumn::Held,
+            Column::Total => OutputColumn::Total,
+            Column::Locked => OutputColumn::Locked,
+            Column::Label => OutputColumn::Label,
+        }
+    }
+}
+
+// Parse the delimiter of the CSV input, a single ASCII character
+fn parse_delimiter(arg: &str) -> Result<u8, String> {
+    match arg.as_bytes() {
+        [delimiter] => Ok(*delimiter),
+        _ => Err(format!("expected a single ASCII character, got `{}`", arg)),
+    }
+}
+
+// Parse a `NAME=COLUMN` rename of an output column
+fn parse_rename(arg: &str) -> Result<(String, Column), String> {
+    let (name, column) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=COLUMN, got `{}`", arg))?;
+    if name.is_empty() {
+        return Err(format!("empty name for column `{}`", column));
+    }
+    let column = Column::from_str(column, false)?;
+    Ok((name.to_string(), column))
+}
+
+// The names of the renamed columns, exiting with status 2 if a column is renamed twice
+fn column_names(renames: Vec<(String, Column)>) -> Vec<(OutputColumn, String)> {
+    let mut column_names: Vec<(OutputColumn, String)> = Vec::new();
+    for (name, column) in renames {
+        let column = OutputColumn::from(column);
+        if column_names.iter().any(|(renamed, _)| *renamed == column) {
+            eprintln!(
+                "Error: column `{}` is renamed more than once",
+                column.name()
+            );
+            std::process::exit(2);
+        }
+        column_names.push((column, name));
+    }
+    column_names
+}
+
+impl From<Dust> for DustAction {
+    fn from(dust: Dust) -> Self {
+        match dust {
+            Dust::Reject => DustAction::Reject,
+            Dust::Sweep => DustAction::Sweep,
+        }
+    }
+}
+
+impl From<Trim> for TrimPolicy {
+    fn from(trim: Trim) -> Self {
+        match trim {
+            Trim::None => TrimPolicy::None,
+            Trim::Headers => TrimPolicy::Headers,
+            Trim::Fields => TrimPolicy::Fields,
+            Trim::All => TrimPolicy::All,
+        }
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let mut engine = PaymentsEngine::new(args.input_file);
-    engine.run();
-    engine.print_accounts(&mut std::io::stdout());
+    match &args.command {
+        Some(Command::Diff { first, second }) => {
+            diff(first, second);
+            return;
+        }
+        #[cfg(feature = "test-utils")]
+        Some(Command::Generate { seed, count }) => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            for tx in payments_engine::generate_transactions(*seed, *count) {
+                writer
+                    .serialize(tx)
+                    .expect("Failed to serialize transactions to stdout");
+            }
+            writer
+                .flush()
+                .expect("Failed to flush transactions to stdout");
+            return;
+        }
+        None => {}
+    }
+    let delimiter = args
+        .delimiter
+        .unwrap_or(if args.decimal_comma { b';' } else { b',' });
+    if args.decimal_comma && delimiter == b',' {
+        eprintln!("Error: --decimal-comma requires a delimiter other than `,`");
+        std::process::exit(2);
+    }
+    let config = EngineConfig {
+        read_buffer_bytes: args.read_buffer_bytes,
+        trim: args.trim.into(),
+        delimiter,
+        decimal_comma: args.decimal_comma,
+        normalize_ids: args.normalize_whitespace_in_ids,
+        lenient_ids: args.lenient_ids,
+        from_timestamp: args.from,
+        to_timestamp: args.to,
+        channels: args.channels,
+        all_or_nothing: args.all_or_nothing,
+        checkpoint_every: args.checkpoint_every,
+        checkpoint_path: args.checkpoint_path.clone(),
+        compact_every: args.compact_every,
+        total_scale: args.total_scale,
+        columns: args
+            .columns
+            .map(|columns| columns.into_iter().map(OutputColumn::from).collect()),
+        column_names: column_names(args.rename_columns),
+        max_errors: args.max_errors,
+        check_invariants_each_step: args.check_invariants_each_step,
+        sample_rate: args.sample_rate.unwrap_or(NonZeroUsize::MIN),
+        parse_threads: args.parse_threads.unwrap_or(NonZeroUsize::MIN),
+        snap_epsilon: args.snap_amounts.then_some(args.snap_epsilon),
+        amount_scale: args.amount_scale,
+        warn_on_rounding: args.warn_on_rounding,
+        round_input: args.round_input,
+        normalize_stored_amounts: args.normalize_stored_amounts,
+        max_open_disputes_per_client: args.max_open_disputes_per_client,
+        max_accounts: args.max_accounts,
+        daily_withdrawal_limit: args.daily_withdrawal_limit,
+        dust_threshold: (!args.allow_dust).then_some(args.dust_threshold),
+        dust_action: args.dust_action.into(),
+        locked_tx_behavior: if args.replay {
+            LockedTxBehavior::Replay
+        } else if args.skip_locked {
+            LockedTxBehavior::SilentSkip
+        } else {
+            LockedTxBehavior::Error
+        },
+        ignore_stray_resolves: args.ignore_stray_resolves,
+        withdrawal_disputes: args.withdrawal_disputes,
+        strict_dispute_rows: args.strict_dispute_rows,
+        buffer_orphan_disputes: args.buffer_orphan_disputes,
+        crlf: args.crlf,
+        output_minor_units: args.output_minor_units.then_some(args.minor_unit_places),
+        negative_only: args.negative_only,
+        grand_total: args.grand_total,
+        forbid_id_reuse: args.forbid_id_reuse,
+        allow_zero_amount: args.allow_zero_amount,
+        negative_is_withdrawal: args.negative_is_withdrawal,
+        with_label: args.with_label,
+        label_policy: if args.latest_label {
+            LabelPolicy::Latest
+        } else {
+            LabelPolicy::First
+        },
+        ..Default::default()
+    };
+    let input_file = args.input_file.expect("Input file is required");
+    let mut engine = PaymentsEngine::with_config(input_file, config);
+    if args.explain {
+        engine = engine.with_explain_writer(std::io::stderr());
+    }
+    if args.progress && std::io::stderr().is_terminal() {
+        engine = engine.with_progress_writer(std::io::stderr());
+    }
+    install_interrupt_handler();
+    engine = engine.with_interrupt_flag(&INTERRUPTED);
+    if let Some(path) = &args.audit_log {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|err| {
+                eprintln!("Error: failed to open {}: {}", path.display(), err);
+                std::process::exit(1);
+            });
+        engine = engine.with_audit_writer(file);
+    }
+    if let Some(path) = &args.dead_letter {
+        let file = std::fs::File::create(path).unwrap_or_else(|err| {
+            eprintln!("Error: failed to create {}: {}", path.display(), err);
+            std::process::exit(1);
+        });
+        engine = engine.with_dead_letter_writer(file);
+    }
+    if let Some(path) = args.checkpoint_path.filter(|path| path.exists()) {
+        let snapshot = std::fs::File::open(&path)
+            .map_err(serde_json::Error::io)
+            .and_then(EngineSnapshot::read_json)
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "Error: failed to read checkpoint {}: {}",
+                    path.display(),
+                    err
+                );
+                std::process::exit(1);
+            });
+        if let Err(err) = engine.resume_from(snapshot) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = engine.run() {
+        eprintln!("Error: {}", err);
+        if matches!(err, Error::TooManyErrors(_)) && args.partial_output_on_abort {
+            engine.print_accounts(&mut std::io::stdout());
+        }
+        std::process::exit(1);
+    }
+    for dispute in engine.orphan_disputes() {
+        eprintln!(
+            "Warning: dispute of transaction (id: {}) for client {} was never matched",
+            dispute.id(),
+            dispute.client()
+        );
+    }
+    if let Some(places) = engine.config().output_minor_units {
+        if let Err(err) = engine.summaries_in_minor_units(places) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    if engine.config().grand_total {
+        if let Err(err) = engine.printed_totals() {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let outputs = [
+        (OutputFormat::Json, args.json_output),
+        #[cfg(feature = "msgpack")]
+        (OutputFormat::MsgPack, args.msgpack_output),
+    ];
+    let mut files: Vec<_> = outputs
+        .into_iter()
+        .filter_map(|(format, path)| Some((format, path?)))
+        .map(|(format, path)| {
+            let file = std::fs::File::create(&path).unwrap_or_else(|err| {
+                eprintln!("Error: failed to create {}: {}", path, err);
+                std::process::exit(1);
+            });
+            (format, file)
+        })
+        .collect();
+    if files.is_empty() {
+        engine.print_accounts(&mut std::io::stdout());
+    } else {
+        let mut stdout = std::io::stdout();
+        let mut sinks: Vec<(OutputFormat, &mut dyn Write)> = vec![(OutputFormat::Csv, &mut stdout)];
+        sinks.extend(
+            files
+                .iter_mut()
+                .map(|(format, file)| (*format, file as &mut dyn Write)),
+        );
+        engine.print_accounts_to_sinks(&mut sinks);
+    }
+    if engine.is_interrupted() {
+        eprintln!("Interrupted, the accounts only reflect the records processed so far");
+    }
+    let res = match args.print_errors_json {
+        Some(Stream::Stdout) => engine.error_report().write_json(&mut std::io::stdout()),
+        Some(Stream::Stderr) => engine.error_report().write_json(&mut std::io::stderr()),
+        None => Ok(()),
+    };
+    res.expect("Failed to print the error report");
+    if engine.is_interrupted() {
+        std::process::exit(130);
+    }
+}
+
+// Set `INTERRUPTED` on the first SIGINT, while a second one terminates the process as usual
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        // SAFETY: `signal` is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+    // SAFETY: the handler only performs async-signal-safe operations
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+// Print the differences between two account outputs, exiting with status 1 if there are any
+fn diff(first: &str, second: &str) {
+    let read = |path: &str| {
+        std::fs::File::open(path)
+            .map_err(csv::Error::from)
+            .and_then(read_summaries)
+            .unwrap_or_else(|err| {
+                eprintln!("Error: failed to read {}: {}", path, err);
+                std::process::exit(2);
+            })
+    };
+    let diffs = diff_summaries(&read(first), &read(second));
+    for diff in &diffs {
+        println!("{}", diff);
+    }
+    if !diffs.is_empty() {
+        std::process::exit(1);
+    }
 }
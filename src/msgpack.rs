@@ -0,0 +1,189 @@
+// MessagePack encoding of the printed accounts, see `OutputFormat::MsgPack`
+
+use std::io::{self, Write};
+
+use crate::{AccountSummary, OutputColumn};
+
+// The summaries are a single array of maps with an entry per printed column, keyed by its name
+// in the CSV header. The funds are strings as in the JSON output, so that they keep their
+// precision.
+pub(crate) fn write_summaries<W: Write>(
+    writer: &mut W,
+    summaries: &[AccountSummary],
+    columns: &[(OutputColumn, &str)],
+) -> io::Result<()> {
+    let mut buf = Vec::new();
+    write_len(&mut buf, summaries.len(), 0x90, 0xdc);
+    for summary in summaries {
+        write_len(&mut buf, columns.len(), 0x80, 0xde);
+        for &(column, name) in columns {
+            write_str(&mut buf, name);
+            match column {
+                OutputColumn::Client => write_uint(&mut buf, summary.client),
+                OutputColumn::Available => write_str(&mut buf, &summary.available.to_string()),
+                OutputColumn::Held => write_str(&mut buf, &summary.held.to_string()),
+                OutputColumn::Total => write_str(&mut buf, &summary.total.to_string()),
+                OutputColumn::Locked => buf.push(if summary.locked { 0xc3 } else { 0xc2 }),
+                OutputColumn::Label => {
+                    write_str(&mut buf, summary.label.as_deref().unwrap_or_default())
+                }
+            }
+        }
+    }
+    writer.write_all(&buf)
+}
+
+// Length of an array or a map, given the markers of its fix and 16-bit formats, the 32-bit
+// format following the 16-bit one
+fn write_len(buf: &mut Vec<u8>, len: usize, fix: u8, marker: u8) {
+    if len < 16 {
+        buf.push(fix | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        buf.push(marker);
+        buf.extend_from_slice(&len.to_be_bytes());
+    } else {
+        let len = u32::try_from(len).expect("Length fits in 32 bits");
+        buf.push(marker + 1);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    let len = value.len();
+    if len < 32 {
+        buf.push(0xa0 | len as u8);
+    } else if let Ok(len) = u8::try_from(len) {
+        buf.extend_from_slice(&[0xd9, len]);
+    } else if let Ok(len) = u16::try_from(len) {
+        buf.push(0xda);
+        buf.extend_from_slice(&len.to_be_bytes());
+    } else {
+        let len = u32::try_from(len).expect("Length fits in 32 bits");
+        buf.push(0xdb);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn write_uint(buf: &mut Vec<u8>, value: u16) {
+    if value < 0x80 {
+        buf.push(value as u8);
+    } else if let Ok(value) = u8::try_from(value) {
+        buf.extend_from_slice(&[0xcc, value]);
+    } else {
+        buf.push(0xcd);
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EngineConfig, OutputColumn, OutputFormat, PaymentsEngine};
+
+    // Decoder of the subset of MessagePack written by `write_summaries`, giving the entries of
+    // every map with their values as printed in CSV
+    struct Decoder<'a>(&'a [u8]);
+
+    impl Decoder<'_> {
+        fn take(&mut self, len: usize) -> &[u8] {
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            taken
+        }
+
+        fn be(&mut self, len: usize) -> usize {
+            self.take(len)
+                .iter()
+                .fold(0, |value, &byte| (value << 8) | usize::from(byte))
+        }
+
+        fn len(&mut self, fix: u8, marker: u8) -> usize {
+            match self.take(1)[0] {
+                byte if byte & 0xf0 == fix => usize::from(byte & 0x0f),
+                byte if byte == marker => self.be(2),
+                byte if byte == marker + 1 => self.be(4),
+                byte => panic!("Unexpected marker {:#x}", byte),
+            }
+        }
+
+        fn value(&mut self) -> String {
+            let len = match self.take(1)[0] {
+                byte if byte < 0x80 => return byte.to_string(),
+                0xcc => return self.be(1).to_string(),
+                0xcd => return self.be(2).to_string(),
+                0xc2 => return false.to_string(),
+                0xc3 => return true.to_string(),
+                byte if byte & 0xe0 == 0xa0 => usize::from(byte & 0x1f),
+                0xd9 => self.be(1),
+                0xda => self.be(2),
+                0xdb => self.be(4),
+                byte => panic!("Unexpected marker {:#x}", byte),
+            };
+            String::from_utf8(self.take(len).to_vec()).expect("String is UTF-8")
+        }
+
+        fn maps(&mut self) -> Vec<Vec<(String, String)>> {
+            let len = self.len(0x90, 0xdc);
+            (0..len)
+                .map(|_| {
+                    let len = self.len(0x80, 0xde);
+                    (0..len).map(|_| (self.value(), self.value())).collect()
+                })
+                .collect()
+        }
+    }
+
+    // The MessagePack output has the header and rows of the CSV output
+    fn assert_same_as_csv(config: EngineConfig, input: &str) {
+        let mut engine = PaymentsEngine::with_config(String::new(), config);
+        engine.process_reader(input.as_bytes());
+        let mut csv_buf = Vec::new();
+        let mut msgpack_buf = Vec::new();
+        engine.print_accounts_to_sinks(&mut [
+            (OutputFormat::Csv, &mut csv_buf),
+            (OutputFormat::MsgPack, &mut msgpack_buf),
+        ]);
+
+        let mut decoder = Decoder(&msgpack_buf);
+        let maps = decoder.maps();
+        assert!(decoder.0.is_empty());
+        let mut reader = csv::Reader::from_reader(csv_buf.as_slice());
+        let header = reader.headers().expect("Header is printed").clone();
+        let rows: Vec<_> = reader.records().map(|row| row.unwrap()).collect();
+        assert_eq!(maps.len(), rows.len());
+        for (map, row) in maps.iter().zip(rows) {
+            let expected: Vec<_> = header
+                .iter()
+                .zip(&row)
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            assert_eq!(*map, expected);
+        }
+    }
+
+    #[test]
+    fn test_write_summaries() {
+        // Enough accounts for the 16-bit array length, with clients in every integer format
+        let mut input = "type,client,tx,amount,client_name\n".to_string();
+        for client in [1u16, 200, 40000].into_iter().chain(10..30) {
+            input.push_str(&format!(
+                "deposit,{},{},1.2500,name {}\n",
+                client, client, client
+            ));
+        }
+        input.push_str("dispute,1,1,,\nchargeback,1,1,,\n");
+
+        assert_same_as_csv(EngineConfig::default(), &input);
+        let config = EngineConfig {
+            with_label: true,
+            ..Default::default()
+        };
+        assert_same_as_csv(config, &input);
+        let config = EngineConfig {
+            columns: Some(vec![OutputColumn::Label, OutputColumn::Client]),
+            column_names: vec![(OutputColumn::Client, "account".to_string())],
+            ..Default::default()
+        };
+        assert_same_as_csv(config, &input);
+    }
+}
@@ -0,0 +1,80 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+// Minimum time between two updates of the progress line
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+// Share of an input processed so far, printed to `writer` on a single line updated
+// periodically, which is completed when the progress is dropped
+pub(crate) struct Progress<'a> {
+    writer: &'a mut dyn Write,
+    read: u64,
+    total: u64,
+    last_update: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    pub(crate) fn new(total: u64, writer: &'a mut dyn Write) -> Self {
+        Self {
+            writer,
+            read: 0,
+            total,
+            last_update: None,
+        }
+    }
+
+    // Count `read` more bytes of the input
+    pub(crate) fn advance(&mut self, read: u64) {
+        self.read += read;
+        if self
+            .last_update
+            .is_none_or(|last| last.elapsed() >= UPDATE_INTERVAL)
+        {
+            self.last_update = Some(Instant::now());
+            self.print();
+        }
+    }
+
+    // Failing to print the progress does not affect the processing
+    fn print(&mut self) {
+        let percent = (self.read.min(self.total) * 100)
+            .checked_div(self.total)
+            .unwrap_or(100);
+        let _ = write!(
+            self.writer,
+            "\rRead {}% ({} of {} bytes)",
+            percent, self.read, self.total
+        );
+        let _ = self.writer.flush();
+    }
+}
+
+impl Drop for Progress<'_> {
+    fn drop(&mut self) {
+        self.print();
+        let _ = writeln!(self.writer);
+    }
+}
+
+// Reader following the share of the input read so far, see `Progress`
+pub(crate) struct ProgressReader<'a, R> {
+    inner: R,
+    progress: Progress<'a>,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    pub(crate) fn new(inner: R, total: u64, writer: &'a mut dyn Write) -> Self {
+        Self {
+            inner,
+            progress: Progress::new(total, writer),
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.advance(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::engine::Error;
+
+// Number of example tx ids kept for each kind of error
+const MAX_EXAMPLES: usize = 5;
+
+/// Errors encountered while processing the input, grouped by kind
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ErrorReport {
+    groups: BTreeMap<&'static str, ErrorGroup>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorGroup {
+    pub count: u64,
+    /// Ids of the first few transactions that failed with this kind of error
+    pub example_tx_ids: Vec<u32>,
+}
+
+impl ErrorReport {
+    /// Record an error raised while processing the transaction with the given id
+    pub fn record(&mut self, err: &Error, tx_id: u32) {
+        self.record_kind(err.kind(), Some(tx_id));
+    }
+
+    /// Record a record that could not be deserialized
+    pub fn record_malformed(&mut self) {
+        self.record_kind("Malformed", None);
+    }
+
+    fn record_kind(&mut self, kind: &'static str, tx_id: Option<u32>) {
+        let group = self.groups.entry(kind).or_default();
+        group.count += 1;
+        if let Some(tx_id) = tx_id {
+            if group.example_tx_ids.len() < MAX_EXAMPLES {
+                group.example_tx_ids.push(tx_id);
+            }
+        }
+    }
+
+    /// Add the errors of `other` to this report, keeping the first few example ids
+    pub fn merge(&mut self, other: &ErrorReport) {
+        for (kind, other_group) in &other.groups {
+            let group = self.groups.entry(kind).or_default();
+            group.count += other_group.count;
+            let free = MAX_EXAMPLES - group.example_tx_ids.len();
+            group
+                .example_tx_ids
+                .extend(other_group.example_tx_ids.iter().take(free));
+        }
+    }
+
+    /// Get the errors of the given kind (the name of the `Error` variant or `Malformed`)
+    pub fn group(&self, kind: &str) -> Option<&ErrorGroup> {
+        self.groups.get(kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Serialize the report as a JSON object keyed by error kind
+    pub fn write_json<W: std::io::Write>(&self, writer: &mut W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(&mut *writer, self)?;
+        writeln!(writer).map_err(serde_json::Error::io)
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Storage of the accounts, transactions or open disputes of a
+/// [`PaymentsEngine`](crate::PaymentsEngine), by their id
+///
+/// The engine keeps its state in [`HashMap`]s by default, other backends can be used through
+/// [`PaymentsEngine::with_stores`](crate::PaymentsEngine::with_stores).
+pub trait Store<K: 'static, V: 'static> {
+    fn get(&self, key: &K) -> Option<&V>;
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V>;
+
+    /// Insert a value, returning the value previously stored under the key
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Remove all the values
+    fn clear(&mut self);
+
+    /// All the entries, in no particular order
+    fn iter(&self) -> impl Iterator<Item = (&K, &V)>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Release the memory that is no longer used, if the backend holds its values in memory
+    fn shrink_to_fit(&mut self) {}
+}
+
+impl<K: Eq + Hash + 'static, V: 'static> Store<K, V> for HashMap<K, V> {
+    fn get(&self, key: &K) -> Option<&V> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        HashMap::iter(self)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        HashMap::shrink_to_fit(self)
+    }
+}